@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+
+/// Command-line arguments split into positionals, `--flags` and `--options value`,
+/// with everything after `--` kept apart for the program being run.
+#[derive(Debug, Default)]
+pub struct Args {
+    positional: Vec<String>,
+    flags: Vec<String>,
+    options: HashMap<String, String>,
+    rest: Vec<String>,
+}
+
+impl Args {
+    /// `with_value` lists the options that consume the following argument.
+    pub fn parse(
+        args: impl IntoIterator<Item = String>,
+        with_value: &[&str],
+    ) -> Result<Self, String> {
+        let mut result = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                result.rest.extend(args.by_ref());
+            } else if with_value.contains(&arg.as_str()) {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))?;
+                result.options.insert(arg, value);
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                result.flags.push(arg);
+            } else {
+                result.positional.push(arg);
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub fn positional(&self, i: usize) -> Option<&str> {
+        self.positional.get(i).map(String::as_str)
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+
+    pub fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    /// The arguments after `--`.
+    pub fn rest(&self) -> &[String] {
+        &self.rest
+    }
+}
@@ -0,0 +1,344 @@
+use crate::{instruction::Instruction, label::Label, program::Program};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    FallThrough,
+    Jump,
+    Branch,
+    Call,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// A run of instructions `start..end` that is only entered at `start`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub id: usize,
+    pub start: usize,
+    pub end: usize,
+    pub function: Option<Label>,
+}
+
+#[derive(Debug)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub edges: Vec<Edge>,
+}
+
+impl Cfg {
+    pub fn build(program: &Program) -> Result<Self, String> {
+        let instructions = &program.0;
+        let labels = program.labels();
+        let block_exits = block_exits(instructions);
+
+        let mut leaders = vec![false; instructions.len()];
+        if let Some(first) = leaders.first_mut() {
+            *first = true;
+        }
+
+        for (i, instruction) in instructions.iter().enumerate() {
+            match instruction {
+                Instruction::Label(_) => leaders[i] = true,
+                Instruction::ScopeOut(_) if block_exits[i] => {}
+                Instruction::Jmp(_)
+                | Instruction::JmpFalse(_, _)
+                | Instruction::JmpFalseOp(..)
+                | Instruction::Call(_)
+                | Instruction::ScopeOut(_)
+                | Instruction::Ret
+                | Instruction::Halt(_) => {
+                    if let Some(next) = leaders.get_mut(i + 1) {
+                        *next = true;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut blocks: Vec<BasicBlock> = vec![];
+        let mut function = None;
+
+        for (i, instruction) in instructions.iter().enumerate() {
+            if let Instruction::Label(label) = instruction {
+                if label.is_function() {
+                    function = Some(label.clone());
+                }
+            }
+
+            if leaders[i] {
+                blocks.push(BasicBlock {
+                    id: blocks.len(),
+                    start: i,
+                    end: i + 1,
+                    function: function.clone(),
+                });
+            } else if let Some(block) = blocks.last_mut() {
+                block.end = i + 1;
+            }
+        }
+
+        let mut block_of = vec![0; instructions.len()];
+        for block in &blocks {
+            block_of[block.start..block.end].fill(block.id);
+        }
+
+        let target = |label: &Label| {
+            labels
+                .get(label)
+                .map(|&i| block_of[i])
+                .ok_or_else(|| format!("No label found for {}", label))
+        };
+
+        let mut edges = vec![];
+
+        for block in &blocks {
+            let next = (block.end < instructions.len()).then(|| block.id + 1);
+            let mut edge = |to: usize, kind: EdgeKind| {
+                edges.push(Edge {
+                    from: block.id,
+                    to,
+                    kind,
+                })
+            };
+
+            let falls_through = match &instructions[block.end - 1] {
+                Instruction::Jmp(label) => {
+                    edge(target(label)?, EdgeKind::Jump);
+                    false
+                }
+                Instruction::JmpFalse(label, _) | Instruction::JmpFalseOp(label, ..) => {
+                    edge(target(label)?, EdgeKind::Branch);
+                    true
+                }
+                Instruction::Call(label) => {
+                    edge(target(label)?, EdgeKind::Call);
+                    true
+                }
+                Instruction::ScopeOut(_) => block_exits[block.end - 1],
+                Instruction::Ret | Instruction::Halt(_) => false,
+                _ => true,
+            };
+
+            if let (true, Some(next)) = (falls_through, next) {
+                edge(next, EdgeKind::FallThrough);
+            }
+        }
+
+        Ok(Self { blocks, edges })
+    }
+
+    /// Blocks reached from `id` by any edge, including calls.
+    pub fn successors(&self, id: usize) -> impl Iterator<Item = usize> + '_ {
+        self.edges
+            .iter()
+            .filter(move |edge| edge.from == id)
+            .map(|edge| edge.to)
+    }
+
+    pub fn to_text(&self, program: &Program) -> String {
+        let mut out = String::new();
+        let mut function = None;
+
+        for block in &self.blocks {
+            if block.function != function {
+                function = block.function.clone();
+                let name = function.as_ref().map_or("<top-level>", Label::as_str);
+                writeln!(out, "{}", name).unwrap();
+            }
+
+            let successors = self
+                .edges
+                .iter()
+                .filter(|edge| edge.from == block.id)
+                .map(|edge| format!("{}{}", edge_prefix(edge.kind), edge.to))
+                .collect::<Vec<_>>();
+
+            writeln!(
+                out,
+                "  block {} [{}..{}] -> [{}]",
+                block.id,
+                block.start,
+                block.end,
+                successors.join(", ")
+            )
+            .unwrap();
+
+            for i in block.start..block.end {
+                writeln!(out, "    {}: {}", i, program.0[i]).unwrap();
+            }
+        }
+
+        out
+    }
+
+    /// Renders the graph in Graphviz format with one cluster per function.
+    pub fn to_dot(&self, program: &Program) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "  node [shape=box, fontname=\"monospace\"];").unwrap();
+
+        let mut cluster = 0;
+        let mut i = 0;
+
+        while i < self.blocks.len() {
+            let function = &self.blocks[i].function;
+            let indent = if function.is_some() { "    " } else { "  " };
+
+            if let Some(function) = function {
+                writeln!(out, "  subgraph cluster_{} {{", cluster).unwrap();
+                writeln!(out, "    label=\"{}\";", escape(function.as_str())).unwrap();
+                cluster += 1;
+            }
+
+            while i < self.blocks.len() && self.blocks[i].function == *function {
+                let block = &self.blocks[i];
+                let text = (block.start..block.end)
+                    .map(|j| format!("{}: {}\\l", j, escape(&program.0[j].to_string())))
+                    .collect::<String>();
+                writeln!(out, "{}b{} [label=\"{}\"];", indent, block.id, text).unwrap();
+                i += 1;
+            }
+
+            if function.is_some() {
+                writeln!(out, "  }}").unwrap();
+            }
+        }
+
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::FallThrough => "",
+                EdgeKind::Jump => " [label=\"jmp\"]",
+                EdgeKind::Branch => " [label=\"false\", color=red]",
+                EdgeKind::Call => " [label=\"call\", style=dashed, color=blue]",
+            };
+            writeln!(out, "  b{} -> b{}{};", edge.from, edge.to, style).unwrap();
+        }
+
+        writeln!(out, "}}").unwrap();
+
+        out
+    }
+}
+
+fn edge_prefix(kind: EdgeKind) -> &'static str {
+    match kind {
+        EdgeKind::FallThrough => "",
+        EdgeKind::Jump => "jmp ",
+        EdgeKind::Branch => "jf ",
+        EdgeKind::Call => "call ",
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Whether each instruction is an `out` that closes a block rather than
+/// returning, assuming each function pairs `in` and `out` in program order,
+/// the way the compiler nests them.
+pub(crate) fn block_exits(instructions: &[Instruction]) -> Vec<bool> {
+    let mut depth = 0;
+
+    instructions
+        .iter()
+        .map(|instruction| match instruction {
+            Instruction::Label(label) if label.is_function() => {
+                depth = 0;
+                false
+            }
+            Instruction::ScopeIn(_) => {
+                depth += 1;
+                false
+            }
+            Instruction::ScopeOut(_) if depth > 0 => {
+                depth -= 1;
+                true
+            }
+            _ => false,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(source: &str) -> (Program, Cfg) {
+        let program = source.parse::<Program>().unwrap();
+        let cfg = Cfg::build(&program).unwrap();
+        (program, cfg)
+    }
+
+    #[test]
+    fn test_conditional_blocks() {
+        let (_, cfg) = build(
+            r#"
+            lbl $$Function__main_$$
+            mov push 1
+            jf $$Else_Conditional_1$$ pop
+            mov push "then"
+            jmp $$Exit_Conditional_1$$
+            lbl $$Else_Conditional_1$$
+            mov push "else"
+            lbl $$Exit_Conditional_1$$
+            out
+            "#,
+        );
+
+        assert_eq!(cfg.blocks.len(), 4);
+        assert_eq!(cfg.successors(0).collect::<Vec<_>>(), vec![2, 1]);
+        assert_eq!(cfg.successors(1).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(cfg.successors(2).collect::<Vec<_>>(), vec![3]);
+        assert_eq!(cfg.successors(3).count(), 0);
+    }
+
+    #[test]
+    fn test_block_out_falls_through() {
+        let (_, cfg) = build(
+            r#"
+            lbl $$Function__main_$$
+            in
+            mov _x_ 1
+            out
+            mov push 2
+            out
+            "#,
+        );
+
+        assert_eq!(cfg.blocks.len(), 1);
+        assert_eq!(cfg.successors(0).count(), 0);
+    }
+
+    #[test]
+    fn test_call_edges() {
+        let (program, cfg) = build(include_str!("../test/fibonacci.4km"));
+        let fib = &cfg.blocks[0];
+
+        let calls = cfg
+            .edges
+            .iter()
+            .filter(|edge| edge.kind == EdgeKind::Call)
+            .collect::<Vec<_>>();
+
+        assert_eq!(calls.len(), 3);
+        assert!(calls.iter().all(|edge| edge.to == fib.id));
+        assert_eq!(cfg.to_dot(&program).matches("subgraph cluster_").count(), 2);
+    }
+
+    #[test]
+    fn test_unknown_label() {
+        let program = "jmp $$Nowhere$$".parse::<Program>().unwrap();
+
+        assert_eq!(
+            Cfg::build(&program).unwrap_err(),
+            "No label found for $$Nowhere$$"
+        );
+    }
+}
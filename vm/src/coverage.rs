@@ -0,0 +1,274 @@
+use crate::{
+    instruction::Instruction,
+    program::Program,
+    vm::{Hook, Vm},
+};
+use std::{collections::BTreeMap, fmt::Display};
+
+/// Records which instructions ran and which way each `jf` went.
+#[derive(Debug)]
+pub struct Coverage {
+    hits: Vec<u64>,
+    /// Times each conditional jump fell through and jumped.
+    branches: Vec<[u64; 2]>,
+    /// The conditional jump executed just before the current instruction,
+    /// and whether it jumps.
+    pending: Option<(usize, bool)>,
+}
+
+impl Coverage {
+    pub fn new(program: &Program) -> Self {
+        Self {
+            hits: vec![0; program.0.len()],
+            branches: vec![[0; 2]; program.0.len()],
+            pending: None,
+        }
+    }
+
+    /// Coverage by source line, where `lines` holds the line of each
+    /// instruction.
+    pub fn record(&self, program: &Program, lines: &[usize]) -> Record {
+        let mut record = Record::default();
+
+        for (i, instruction) in program.0.iter().enumerate() {
+            let line = lines[i];
+            *record.lines.entry(line).or_default() += self.hits[i];
+
+            if let Instruction::JmpFalse(..) | Instruction::JmpFalseOp(..) = instruction {
+                for (branch, count) in self.branches[i].iter().enumerate() {
+                    let count = (self.hits[i] > 0).then_some(*count);
+                    merge(record.branches.entry((line, branch)).or_default(), count);
+                }
+            }
+        }
+
+        record
+    }
+}
+
+impl Hook for Coverage {
+    fn step(&mut self, vm: &Vm, program: &Program, i: usize) {
+        if let Some((jump, taken)) = self.pending.take() {
+            self.branches[jump][taken as usize] += 1;
+        }
+
+        if let Some(instruction) = program.0.get(i) {
+            self.hits[i] += 1;
+            self.pending = jumps(vm, instruction).map(|taken| (i, taken));
+        }
+    }
+}
+
+/// Whether the conditional jump `instruction` is about to jump, evaluated on
+/// copies of its operands so that a `jf` to the very next instruction still
+/// counts as taken. `None` for other instructions or if the jump will fail.
+fn jumps(vm: &Vm, instruction: &Instruction) -> Option<bool> {
+    let scope = vm.scopes().last()?;
+    let stack = vm.stack();
+    let mut stack = stack[stack.len().saturating_sub(2)..].to_vec();
+
+    let condition = match instruction {
+        Instruction::JmpFalse(_, operand) => operand.get_value(scope, &mut stack).ok()?,
+        Instruction::JmpFalseOp(_, _, operand1, operand2) => {
+            let value1 = operand1.get_value(scope, &mut stack).ok()?;
+            let value2 = operand2.get_value(scope, &mut stack).ok()?;
+            instruction.evaluate(&[value1, value2]).ok()?
+        }
+        _ => return None,
+    };
+
+    Some(!condition.is_truthy())
+}
+
+/// The lcov record of one source file. Branch `0` of a line is the fall
+/// through of its `jf` and branch `1` the jump; `None` means the `jf` never ran.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Record {
+    pub lines: BTreeMap<usize, u64>,
+    pub branches: BTreeMap<(usize, usize), Option<u64>>,
+}
+
+fn merge(count: &mut Option<u64>, other: Option<u64>) {
+    *count = match (*count, other) {
+        (None, None) => None,
+        (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+    };
+}
+
+impl Record {
+    pub fn merge(&mut self, other: &Self) {
+        for (line, hits) in &other.lines {
+            *self.lines.entry(*line).or_default() += hits;
+        }
+        for (branch, count) in &other.branches {
+            merge(self.branches.entry(*branch).or_default(), *count);
+        }
+    }
+}
+
+/// An lcov tracefile, keyed by source file.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Report(pub BTreeMap<String, Record>);
+
+impl Report {
+    /// Parses the `SF`, `DA` and `BRDA` entries of a tracefile.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut report = Self::default();
+        let mut current = None;
+
+        for line in s.lines().map(str::trim) {
+            let invalid = || format!("Invalid lcov line: {}", line);
+            let (key, value) = line.split_once(':').unwrap_or((line, ""));
+            let fields = value.split(',').collect::<Vec<_>>();
+            let number = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok());
+
+            match key {
+                "SF" => current = Some(value.to_string()),
+                "end_of_record" => current = None,
+                "DA" | "BRDA" => {
+                    let record = report
+                        .0
+                        .entry(current.clone().ok_or_else(invalid)?)
+                        .or_default();
+                    let line = number(0).ok_or_else(invalid)? as usize;
+
+                    if key == "DA" {
+                        *record.lines.entry(line).or_default() += number(1).ok_or_else(invalid)?;
+                    } else {
+                        let branch = number(2).ok_or_else(invalid)? as usize;
+                        let count = match fields.get(3) {
+                            Some(&"-") => None,
+                            _ => Some(number(3).ok_or_else(invalid)?),
+                        };
+                        merge(record.branches.entry((line, branch)).or_default(), count);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(report)
+    }
+
+    pub fn add(&mut self, path: &str, record: &Record) {
+        self.0.entry(path.to_string()).or_default().merge(record);
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (path, record) in &self.0 {
+            writeln!(f, "TN:")?;
+            writeln!(f, "SF:{}", path)?;
+
+            for ((line, branch), count) in &record.branches {
+                match count {
+                    Some(count) => writeln!(f, "BRDA:{},0,{},{}", line, branch, count)?,
+                    None => writeln!(f, "BRDA:{},0,{},-", line, branch)?,
+                }
+            }
+            let hit = |count: &&Option<u64>| matches!(count, Some(count) if *count > 0);
+            writeln!(f, "BRF:{}", record.branches.len())?;
+            writeln!(f, "BRH:{}", record.branches.values().filter(hit).count())?;
+
+            for (line, hits) in &record.lines {
+                writeln!(f, "DA:{},{}", line, hits)?;
+            }
+            writeln!(f, "LF:{}", record.lines.len())?;
+            writeln!(
+                f,
+                "LH:{}",
+                record.lines.values().filter(|hits| **hits > 0).count()
+            )?;
+
+            writeln!(f, "end_of_record")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static BRANCH: &str = r#"
+        // function _main_
+        lbl $$Function__main_$$
+        mov _x_ 1
+        jf $$Else$$ _x_
+        mov push 1
+        out
+        lbl $$Else$$
+        jf $$End$$ 0
+        lbl $$End$$
+        mov push 2
+        out
+    "#;
+
+    fn record(source: &str) -> Record {
+        let (program, map) = Program::parse_with_source_map(source, "").unwrap();
+        let mut coverage = Coverage::new(&program);
+        Vm::default()
+            .run_with_hook(&program, &mut coverage)
+            .unwrap();
+        coverage.record(&program, &map.lines)
+    }
+
+    #[test]
+    fn test_lines_and_branches() {
+        let record = record(BRANCH);
+
+        assert_eq!(record.lines[&3], 1);
+        assert_eq!(record.lines[&6], 1);
+        assert_eq!(record.lines[&8], 0);
+        assert_eq!(record.branches[&(5, 0)], Some(1));
+        assert_eq!(record.branches[&(5, 1)], Some(0));
+        assert_eq!(record.branches[&(9, 0)], None);
+    }
+
+    #[test]
+    fn test_jump_to_next_instruction() {
+        let record = record(
+            r#"
+            lbl $$Function__main_$$
+            mov push 0
+            jf $$Next$$ pop
+            lbl $$Next$$
+            mov push 1
+            out
+            "#,
+        );
+
+        assert_eq!(record.branches[&(4, 0)], Some(0));
+        assert_eq!(record.branches[&(4, 1)], Some(1));
+    }
+
+    #[test]
+    fn test_recursion_takes_both_branches() {
+        let record = record(include_str!("../test/fibonacci.4km"));
+
+        assert!(!record.branches.is_empty());
+        assert!(record
+            .branches
+            .values()
+            .all(|count| count.is_some_and(|count| count > 0)));
+    }
+
+    #[test]
+    fn test_merge_roundtrip() {
+        let record = record(BRANCH);
+        let mut report = Report::default();
+        report.add("branch.4km", &record);
+
+        let mut merged = Report::parse(&report.to_string()).unwrap();
+        assert_eq!(merged, report);
+
+        merged.add("branch.4km", &record);
+        let merged = &merged.0["branch.4km"];
+        assert_eq!(merged.lines[&3], 2);
+        assert_eq!(merged.branches[&(5, 0)], Some(2));
+        assert_eq!(merged.branches[&(5, 1)], Some(0));
+        assert_eq!(merged.branches[&(9, 0)], None);
+    }
+}
@@ -0,0 +1,506 @@
+use crate::{
+    debug::{Debugger, Stop},
+    exit,
+    input::{Input, Replay},
+    label::Label,
+    output::Buffer,
+    program::{Location, Program, SourceMap},
+    value::Value,
+    vm::{Vm, MAIN_FN},
+    wire,
+};
+use serde_json::{json, Value as Json};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    io::{BufRead, Write},
+    path::Path,
+};
+
+/// Instructions that can be stepped back in a debug session.
+static HISTORY: usize = 100_000;
+
+/// The only thread of a program.
+static THREAD: u64 = 1;
+
+/// Variables reference of the value stack. Frame `k`, innermost first, has
+/// its locals at `k + 2`.
+static STACK: u64 = 1;
+
+/// Reads and writes Debug Adapter Protocol messages.
+struct Connection<R: BufRead, W: Write> {
+    reader: R,
+    writer: W,
+    seq: u64,
+}
+
+impl<R: BufRead, W: Write> Connection<R, W> {
+    fn read(&mut self) -> Result<Option<Json>, String> {
+        wire::read(&mut self.reader)
+    }
+
+    fn send(&mut self, mut message: Json) -> Result<(), String> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        wire::write(&mut self.writer, &message)
+    }
+
+    fn respond(&mut self, request: &Json, body: Result<Json, String>) -> Result<(), String> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": body.is_ok(),
+        });
+        match body {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> Result<(), String> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+}
+
+/// Serves one debug session, reading requests from `reader` and writing
+/// responses and events to `writer`.
+pub fn serve(reader: impl BufRead, writer: impl Write) -> Result<(), String> {
+    let mut connection = Connection {
+        reader,
+        writer,
+        seq: 0,
+    };
+
+    let (launch, program, map) = loop {
+        let Some(request) = connection.read()? else {
+            return Ok(());
+        };
+
+        match request["command"].as_str().unwrap_or_default() {
+            "initialize" => connection.respond(
+                &request,
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsStepBack": true,
+                })),
+            )?,
+            "launch" => match load(&request["arguments"]) {
+                Ok((program, map)) => break (request, program, map),
+                Err(e) => connection.respond(&request, Err(e))?,
+            },
+            "disconnect" => return connection.respond(&request, Ok(Json::Null)),
+            _ => connection.respond(&request, Err("Launch a program first".to_string()))?,
+        }
+    };
+
+    let arguments = &launch["arguments"];
+    let output = Buffer::default();
+    let debugger = input(arguments, &program).and_then(|input| {
+        let vm = Vm::with_io(input, Box::new(output.clone()));
+        Debugger::new(&program, vm, &Label::function(MAIN_FN), vec![], HISTORY)
+    });
+    let mut session = match debugger {
+        Ok(debugger) => Session {
+            debugger,
+            map,
+            output,
+            breakpoints: HashMap::new(),
+        },
+        Err(e) => return connection.respond(&launch, Err(e)),
+    };
+    connection.respond(&launch, Ok(Json::Null))?;
+    connection.event("initialized", Json::Null)?;
+
+    let stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+    while let Some(request) = connection.read()? {
+        let command = request["command"].as_str().unwrap_or_default();
+        let arguments = &request["arguments"];
+
+        let stop = match command {
+            "disconnect" | "terminate" => {
+                connection.respond(&request, Ok(Json::Null))?;
+                return connection.event("terminated", Json::Null);
+            }
+            "configurationDone" if stop_on_entry => {
+                connection.respond(&request, Ok(Json::Null))?;
+                connection.event("stopped", json!({ "reason": "entry", "threadId": THREAD }))?;
+                continue;
+            }
+            "configurationDone" | "continue" => session.debugger.resume(),
+            "next" => session.step_over(),
+            "stepIn" => session.step_in(),
+            "stepOut" => session.step_out(),
+            "stepBack" => session.step_back(),
+            "reverseContinue" => session.debugger.reverse(),
+            _ => {
+                let body = session.request(command, arguments);
+                connection.respond(&request, body)?;
+                continue;
+            }
+        };
+
+        let body = match command {
+            "continue" => json!({ "allThreadsContinued": true }),
+            _ => Json::Null,
+        };
+        connection.respond(&request, Ok(body))?;
+        if session.report(&mut connection, stop)? {
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads the program named by the launch arguments.
+fn load(arguments: &Json) -> Result<(Program, SourceMap), String> {
+    let path = arguments["program"]
+        .as_str()
+        .ok_or("Missing program in the launch arguments")?;
+    let source = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+    Program::parse_with_source_map(&source, path)
+}
+
+/// Input is replayed from the `replay` recording, since stdin carries the
+/// protocol.
+fn input(arguments: &Json, program: &Program) -> Result<Box<dyn Input>, String> {
+    let Some(path) = arguments["replay"].as_str() else {
+        return Ok(Box::new(NoInput));
+    };
+    let recording =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+
+    Ok(Box::new(Replay::parse(&recording, program.fingerprint())?))
+}
+
+#[derive(Debug)]
+struct NoInput;
+
+impl Input for NoInput {
+    fn read_line(&mut self) -> Result<String, String> {
+        Err("Reading input in a debug session requires a `replay` recording".to_string())
+    }
+}
+
+struct Session<'a> {
+    debugger: Debugger<'a>,
+    map: SourceMap,
+    output: Buffer,
+    /// Instructions with a breakpoint, by source path.
+    breakpoints: HashMap<String, BTreeSet<usize>>,
+}
+
+impl Session<'_> {
+    fn location(&self, vm: &Vm) -> Option<Location> {
+        self.map.location(vm.ip())
+    }
+
+    fn step_in(&mut self) -> Stop {
+        let start = self.location(self.debugger.vm());
+        let map = &self.map;
+        self.debugger
+            .step_until(|vm| map.location(vm.ip()) != start)
+    }
+
+    /// Steps to the next line of the current function, running a `call`
+    /// until its `out`.
+    fn step_over(&mut self) -> Stop {
+        let start = self.location(self.debugger.vm());
+        let depth = self.debugger.vm().depth();
+        let map = &self.map;
+        self.debugger
+            .step_until(|vm| vm.depth() <= depth && map.location(vm.ip()) != start)
+    }
+
+    fn step_out(&mut self) -> Stop {
+        let depth = self.debugger.vm().depth();
+        self.debugger.step_until(|vm| vm.depth() < depth)
+    }
+
+    fn step_back(&mut self) -> Stop {
+        let start = self.location(self.debugger.vm());
+        let depth = self.debugger.vm().depth();
+        let map = &self.map;
+        self.debugger
+            .back_until(|vm| vm.depth() <= depth && map.location(vm.ip()) != start)
+    }
+
+    /// Sends the events for `stop` and returns whether the session ended.
+    fn report<R: BufRead, W: Write>(
+        &mut self,
+        connection: &mut Connection<R, W>,
+        stop: Stop,
+    ) -> Result<bool, String> {
+        let output = self.output.take();
+        if !output.is_empty() {
+            connection.event("output", json!({ "category": "stdout", "output": output }))?;
+        }
+
+        let (reason, text) = match stop {
+            Stop::Step => ("step", None),
+            Stop::Breakpoint => ("breakpoint", None),
+            Stop::Start => ("step", Some("Reached the start of the history".to_string())),
+            Stop::Error(e) => ("exception", Some(e)),
+            Stop::Finished(result) => {
+                connection.event(
+                    "output",
+                    json!({ "category": "console", "output": format!("Result: {:?}\n", result) }),
+                )?;
+                connection.event("exited", json!({ "exitCode": exit::status(&result) }))?;
+                connection.event("terminated", Json::Null)?;
+                return Ok(true);
+            }
+        };
+
+        connection.event(
+            "stopped",
+            json!({ "reason": reason, "text": text, "threadId": THREAD }),
+        )?;
+        Ok(false)
+    }
+
+    fn request(&mut self, command: &str, arguments: &Json) -> Result<Json, String> {
+        match command {
+            "setBreakpoints" => Ok(self.set_breakpoints(arguments)),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "main" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => {
+                let frame = arguments["frameId"].as_u64().unwrap_or(0);
+                Ok(json!({ "scopes": [
+                    { "name": "Locals", "variablesReference": frame + 2, "expensive": false },
+                    { "name": "Stack", "variablesReference": STACK, "expensive": false },
+                ] }))
+            }
+            "variables" => self.variables(arguments["variablesReference"].as_u64().unwrap_or(0)),
+            _ => Err(format!("Unsupported request: {}", command)),
+        }
+    }
+
+    /// Puts a breakpoint on the first instruction of each requested line.
+    fn set_breakpoints(&mut self, arguments: &Json) -> Json {
+        let path = arguments["source"]["path"].as_str().unwrap_or_default();
+        let lines = arguments["breakpoints"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|breakpoint| breakpoint["line"].as_u64());
+
+        let mut instructions = BTreeSet::new();
+        let breakpoints = lines
+            .map(|line| {
+                let instruction = (0..self.map.lines.len()).find(|&i| {
+                    self.map.location(i).is_some_and(|location| {
+                        location.line as u64 == line && same_file(&location.file, path)
+                    })
+                });
+                instructions.extend(instruction);
+                json!({ "verified": instruction.is_some(), "line": line })
+            })
+            .collect::<Vec<_>>();
+
+        self.breakpoints.insert(path.to_string(), instructions);
+        self.debugger.breakpoints = self.breakpoints.values().flatten().copied().collect();
+
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn stack_trace(&self) -> Json {
+        let frames = self
+            .debugger
+            .vm()
+            .backtrace()
+            .frames
+            .into_iter()
+            .enumerate()
+            .map(|(id, (label, i))| {
+                let location = self.map.location(i);
+                let mut frame = json!({
+                    "id": id,
+                    "name": label.to_string(),
+                    "line": location.as_ref().map_or(0, |location| location.line),
+                    "column": location.as_ref().and_then(|location| location.column).unwrap_or(1),
+                    "instructionPointerReference": i.to_string(),
+                });
+                if let Some(location) = location {
+                    frame["source"] = json!({ "path": location.file });
+                }
+                frame
+            })
+            .collect::<Vec<_>>();
+
+        json!({ "totalFrames": frames.len(), "stackFrames": frames })
+    }
+
+    fn variables(&self, reference: u64) -> Result<Json, String> {
+        let vm = self.debugger.vm();
+
+        let variables = if reference == STACK {
+            // Top of the stack first, as it is read by `pop`.
+            vm.stack()
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, value)| variable(&i.to_string(), value))
+                .collect()
+        } else {
+            let (frames, scopes) = (vm.frames(), vm.scopes());
+            // The innermost scope of a frame, which may be a block, lies just
+            // below the scope of the frame it called.
+            let scope = reference
+                .checked_sub(2)
+                .map(|frame| frame as usize)
+                .filter(|frame| *frame < frames.len())
+                .and_then(|frame| match frame {
+                    0 => scopes.len().checked_sub(1),
+                    _ => frames[frames.len() - frame].scope.checked_sub(1),
+                })
+                .ok_or_else(|| format!("Unknown variables reference: {}", reference))?;
+
+            let mut locals = scopes[scope].iter().collect::<Vec<_>>();
+            locals.sort_by_key(|(name, _)| *name);
+            locals
+                .into_iter()
+                .map(|(name, value)| variable(name, value))
+                .collect::<Vec<_>>()
+        };
+
+        Ok(json!({ "variables": variables }))
+    }
+}
+
+fn variable(name: &str, value: &Value) -> Json {
+    let kind = match value {
+        Value::Float(_) => "float",
+        Value::String(_) => "string",
+    };
+
+    json!({ "name": name, "value": value.literal(), "type": kind, "variablesReference": 0 })
+}
+
+/// Editors send absolute paths, while source maps hold them as written.
+fn same_file(a: &str, b: &str) -> bool {
+    let (a, b) = (Path::new(a), Path::new(b));
+    a.ends_with(b) || b.ends_with(a)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(requests: &[Json]) -> Vec<Json> {
+        let requests = requests
+            .iter()
+            .enumerate()
+            .map(|(seq, request)| {
+                let mut request = request.clone();
+                request["seq"] = json!(seq + 1);
+                request["type"] = json!("request");
+                request
+            })
+            .collect::<Vec<_>>();
+
+        let mut output = vec![];
+        serve(&wire::frame(&requests)[..], &mut output).unwrap();
+        wire::unframe(&output)
+    }
+
+    fn find<'a>(messages: &'a [Json], kind: &str, name: &str) -> Vec<&'a Json> {
+        let key = if kind == "event" { "event" } else { "command" };
+        messages
+            .iter()
+            .filter(|message| message["type"] == kind && message[key] == name)
+            .collect()
+    }
+
+    #[test]
+    fn test_breakpoint_stack_and_variables() {
+        let messages = session(&[
+            json!({ "command": "initialize", "arguments": {} }),
+            json!({ "command": "launch", "arguments": { "program": "test/factorial.4km" } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": "/home/user/vm/test/factorial.4km" },
+                "breakpoints": [{ "line": 36 }, { "line": 3 }],
+            } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 2 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            json!({ "command": "stepOut", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+        ]);
+
+        let breakpoints = &find(&messages, "response", "setBreakpoints")[0]["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[1]["verified"], false);
+        assert_eq!(
+            find(&messages, "event", "stopped")[0]["body"]["reason"],
+            "breakpoint"
+        );
+
+        let traces = find(&messages, "response", "stackTrace");
+        let frames = traces[0]["body"]["stackFrames"].as_array().unwrap();
+        assert_eq!(frames.len(), 12);
+        assert_eq!(frames[0]["line"], 36);
+        assert_eq!(frames[0]["name"], "$$Function__factorial_$$");
+        assert_eq!(frames[11]["name"], "$$Function__main_$$");
+        assert_eq!(
+            traces[1]["body"]["stackFrames"].as_array().unwrap().len(),
+            11
+        );
+
+        let variables = find(&messages, "response", "variables");
+        assert_eq!(
+            variables[0]["body"]["variables"],
+            json!([{ "name": "_n_", "value": "0", "type": "float", "variablesReference": 0 }])
+        );
+        assert_eq!(variables[1]["body"]["variables"][0]["value"], "1");
+
+        assert_eq!(
+            find(&messages, "event", "output")[0]["body"]["output"],
+            "3628800\n"
+        );
+        assert_eq!(
+            find(&messages, "event", "exited")[0]["body"]["exitCode"],
+            exit::OUT_OF_RANGE
+        );
+        assert_eq!(find(&messages, "event", "terminated").len(), 1);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let messages = session(&[
+            json!({ "command": "initialize", "arguments": {} }),
+            json!({ "command": "launch", "arguments": {
+                "program": "test/factorial.4km",
+                "stopOnEntry": true,
+            } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "next", "arguments": { "threadId": 1 } }),
+            json!({ "command": "next", "arguments": { "threadId": 1 } }),
+            json!({ "command": "next", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stepBack", "arguments": { "threadId": 1 } }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "disconnect" }),
+        ]);
+
+        let reasons = find(&messages, "event", "stopped")
+            .iter()
+            .map(|event| event["body"]["reason"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(reasons, ["entry", "step", "step", "step", "step"]);
+
+        let lines = find(&messages, "response", "stackTrace")
+            .iter()
+            .map(|response| response["body"]["stackFrames"][0]["line"].clone())
+            .collect::<Vec<_>>();
+        // The third `next` steps over the call on line 7.
+        assert_eq!(lines, [json!(9), json!(7)]);
+        assert!(find(&messages, "event", "output").is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use crate::{history::History, label::Label, program::Program, value::Value, vm::Vm};
+use std::collections::BTreeSet;
+
+/// Why the debugger stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    Step,
+    Breakpoint,
+    /// Stepping back reached the oldest instruction in the history.
+    Start,
+    Finished(Value),
+    Error(String),
+}
+
+/// Steps a run forwards and backwards, stopping at breakpoints.
+#[derive(Debug)]
+pub struct Debugger<'a> {
+    program: &'a Program,
+    vm: Vm,
+    history: History,
+    pub breakpoints: BTreeSet<usize>,
+    result: Option<Value>,
+}
+
+impl<'a> Debugger<'a> {
+    /// Starts `vm` and stops at the label of main. `capacity` bounds the
+    /// number of instructions that can be stepped back.
+    /// Starts debugging `program` at the function labelled `entry`, which
+    /// receives `args` like a call would.
+    pub fn new(
+        program: &'a Program,
+        mut vm: Vm,
+        entry: &Label,
+        args: Vec<Value>,
+        capacity: usize,
+    ) -> Result<Self, String> {
+        vm.enter(program, entry, args)?;
+
+        Ok(Self {
+            program,
+            vm,
+            history: History::new(capacity),
+            breakpoints: BTreeSet::new(),
+            result: None,
+        })
+    }
+
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn step(&mut self) -> Stop {
+        if let Some(result) = &self.result {
+            return Stop::Finished(result.clone());
+        }
+
+        match self.history.step(&mut self.vm, self.program) {
+            Ok(Some(result)) => {
+                self.result = Some(result.clone());
+                Stop::Finished(result)
+            }
+            Ok(None) => Stop::Step,
+            Err(e) => Stop::Error(e),
+        }
+    }
+
+    pub fn back(&mut self) -> Stop {
+        if !self.history.back(&mut self.vm) {
+            return Stop::Start;
+        }
+
+        self.result = None;
+        Stop::Step
+    }
+
+    /// Steps until a breakpoint, the end of the run or an error.
+    pub fn resume(&mut self) -> Stop {
+        self.step_until(|_| false)
+    }
+
+    /// Steps back until a breakpoint or the start of the history.
+    pub fn reverse(&mut self) -> Stop {
+        self.back_until(|_| false)
+    }
+
+    /// Steps at least once and until `done` holds for the VM, or like
+    /// [`Self::resume`] would stop.
+    pub fn step_until(&mut self, done: impl FnMut(&Vm) -> bool) -> Stop {
+        self.repeat(Self::step, done)
+    }
+
+    pub fn back_until(&mut self, done: impl FnMut(&Vm) -> bool) -> Stop {
+        self.repeat(Self::back, done)
+    }
+
+    fn repeat(
+        &mut self,
+        mut step: impl FnMut(&mut Self) -> Stop,
+        mut done: impl FnMut(&Vm) -> bool,
+    ) -> Stop {
+        loop {
+            match step(self) {
+                Stop::Step if self.breakpoints.contains(&self.vm.ip()) => return Stop::Breakpoint,
+                Stop::Step if done(&self.vm) => return Stop::Step,
+                Stop::Step => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    /// Steps back to the instruction that last wrote `name` in the current
+    /// frame and returns its index, or stays put if the history has none.
+    pub fn last_write(&mut self, name: &str) -> Option<usize> {
+        let steps = self.history.last_write(name, self.vm.depth())?;
+        for _ in 0..steps {
+            self.back();
+        }
+
+        Some(self.vm.ip())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::MAIN_FN;
+
+    static COUNTER: &str = r#"
+        lbl $$Function__main_$$
+        mov _i_ 0
+        lbl $$Loop$$
+        mov push _i_
+        mov push 3
+        < pop pop push
+        jf $$End$$ pop
+        mov push 1
+        mov push _i_
+        + pop pop _i_
+        jmp $$Loop$$
+        lbl $$End$$
+        mov push _i_
+        out
+    "#;
+
+    #[test]
+    fn test_breakpoints_both_ways() {
+        let program = COUNTER.parse::<Program>().unwrap();
+        let mut debugger = Debugger::new(
+            &program,
+            Vm::default(),
+            &Label::function(MAIN_FN),
+            vec![],
+            1000,
+        )
+        .unwrap();
+        debugger.breakpoints.insert(9);
+
+        assert_eq!(debugger.resume(), Stop::Breakpoint);
+        assert_eq!(debugger.vm().variable("_i_"), Some(&Value::Float(0.0)));
+        assert_eq!(debugger.resume(), Stop::Breakpoint);
+        assert_eq!(debugger.vm().variable("_i_"), Some(&Value::Float(1.0)));
+
+        assert_eq!(debugger.reverse(), Stop::Breakpoint);
+        assert_eq!(debugger.vm().variable("_i_"), Some(&Value::Float(0.0)));
+        assert_eq!(debugger.reverse(), Stop::Start);
+
+        debugger.breakpoints.clear();
+        assert_eq!(debugger.resume(), Stop::Finished(Value::Float(3.0)));
+        assert_eq!(debugger.step(), Stop::Finished(Value::Float(3.0)));
+    }
+
+    #[test]
+    fn test_last_write() {
+        let program = COUNTER.parse::<Program>().unwrap();
+        let mut debugger = Debugger::new(
+            &program,
+            Vm::default(),
+            &Label::function(MAIN_FN),
+            vec![],
+            1000,
+        )
+        .unwrap();
+        debugger.resume();
+
+        assert_eq!(debugger.last_write("_i_"), Some(9));
+        assert_eq!(debugger.vm().variable("_i_"), Some(&Value::Float(2.0)));
+        assert_eq!(debugger.last_write("_j_"), None);
+    }
+
+    #[test]
+    fn test_entry() {
+        let program = "lbl $$Function__main_$$\nhalt 1\nlbl $$Function__double_$$\npop _n_\n* _n_ 2 push\nret"
+            .parse::<Program>()
+            .unwrap();
+        let entry = Label::function("_double_");
+        let mut debugger = Debugger::new(
+            &program,
+            Vm::default(),
+            &entry,
+            vec![Value::Float(4.0)],
+            1000,
+        )
+        .unwrap();
+
+        assert_eq!(debugger.vm().ip(), 2);
+        assert_eq!(debugger.resume(), Stop::Finished(Value::Float(8.0)));
+    }
+}
@@ -0,0 +1,113 @@
+use crate::value::Value;
+
+/// Why a command failed. Each kind exits with its own status, taken from
+/// sysexits(3), so that scripts can tell them apart.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    /// Invalid command-line arguments.
+    Usage(String),
+    /// The program, or a snapshot of it, could not be parsed or loaded.
+    Program(String),
+    /// The program failed while running.
+    Runtime(String),
+    /// A file could not be read or written.
+    Io(String),
+    /// Anything else, e.g. failing golden tests.
+    Other(String),
+}
+
+impl Failure {
+    pub fn status(&self) -> u8 {
+        match self {
+            Self::Usage(_) => 64,
+            Self::Program(_) => 65,
+            Self::Runtime(_) => 70,
+            Self::Io(_) => 74,
+            Self::Other(_) => 1,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Usage(message)
+            | Self::Program(message)
+            | Self::Runtime(message)
+            | Self::Io(message)
+            | Self::Other(message) => message,
+        }
+    }
+}
+
+/// The status a run exits with when its result is an integer that is not a
+/// valid program status.
+pub const OUT_OF_RANGE: u8 = 63;
+
+/// The exit status of a run that ended with `value`, from main returning it
+/// or from `halt`. Integers in `0..=62` are their own status, except `1`,
+/// which stays reserved for failures along with sysexits' `64..` range;
+/// other integers exit with [`OUT_OF_RANGE`] and anything else with success.
+pub fn status(value: &Value) -> u8 {
+    match value {
+        Value::Float(f) if f.is_finite() && f.fract() == 0.0 => match *f as i64 {
+            status @ (0 | 2..=62) => status as u8,
+            _ => OUT_OF_RANGE,
+        },
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        let status = |s: &str| status(&Value::from_str(s).unwrap());
+
+        assert_eq!(status("0"), 0);
+        assert_eq!(status("3"), 3);
+        assert_eq!(status("62"), 62);
+        assert_eq!(status("256"), OUT_OF_RANGE);
+        assert_eq!(status("-1"), OUT_OF_RANGE);
+        assert_eq!(status("1.5"), 0);
+        assert_eq!(status("\"error\""), 0);
+    }
+
+    #[test]
+    fn test_failures_have_distinct_statuses() {
+        let statuses = [
+            Failure::Usage(String::new()),
+            Failure::Program(String::new()),
+            Failure::Runtime(String::new()),
+            Failure::Io(String::new()),
+            Failure::Other(String::new()),
+        ]
+        .map(|failure| failure.status());
+
+        for (i, status) in statuses.iter().enumerate() {
+            assert!(!statuses[i + 1..].contains(status));
+        }
+    }
+
+    #[test]
+    fn test_results_never_look_like_failures() {
+        let failures = [
+            Failure::Usage(String::new()),
+            Failure::Program(String::new()),
+            Failure::Runtime(String::new()),
+            Failure::Io(String::new()),
+            Failure::Other(String::new()),
+        ]
+        .map(|failure| failure.status());
+
+        for result in -300..300 {
+            let status = status(&Value::from_str(&result.to_string()).unwrap());
+            assert!(
+                !failures.contains(&status),
+                "{} exits with {}",
+                result,
+                status
+            );
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{instruction::Instruction, op::Op, operand::Operand, program::Program, target::Target};
+
+/// Fuses the sequences the compiler emits for every expression and condition
+/// into superinstructions:
+///
+/// - `mov push A`, `mov push B`, `<op> pop pop push` into `<op> B A push`
+/// - `<op> A B push`, `jf L pop` into `jf.<op> L A B`
+///
+/// Also returns the index in `program` of the first instruction each fused
+/// instruction was made from.
+pub fn fuse(program: Program) -> (Program, Vec<usize>) {
+    let mut out: Vec<Instruction> = Vec::with_capacity(program.0.len());
+    let mut origin = Vec::with_capacity(program.0.len());
+
+    for (mut i, instruction) in program.0.into_iter().enumerate() {
+        let fused = match (&out[..], &instruction) {
+            (
+                [.., Instruction::Mov(Target::Push, a), Instruction::Mov(Target::Push, b)],
+                instruction,
+            ) if *a != Operand::Pop && *b != Operand::Pop && is_pop_pop_push(instruction) => {
+                let mut fused = instruction.clone();
+                let mut operands = fused.operands_mut();
+                *operands[0] = b.clone();
+                *operands[1] = a.clone();
+                out.truncate(out.len() - 2);
+                i = origin[origin.len() - 2];
+                origin.truncate(origin.len() - 2);
+                fused
+            }
+            ([.., last], Instruction::JmpFalse(label, Operand::Pop)) => {
+                match (
+                    Op::from_instruction(last),
+                    &last.operands()[..],
+                    last.target(),
+                ) {
+                    (Some(op), [a, b], Some(Target::Push))
+                        if **a != Operand::Pop && **b != Operand::Pop =>
+                    {
+                        let fused =
+                            Instruction::JmpFalseOp(label.clone(), op, (*a).clone(), (*b).clone());
+                        out.pop();
+                        i = origin.pop().unwrap();
+                        fused
+                    }
+                    _ => instruction,
+                }
+            }
+            _ => instruction,
+        };
+
+        out.push(fused);
+        origin.push(i);
+    }
+
+    (Program(out), origin)
+}
+
+fn is_pop_pop_push(instruction: &Instruction) -> bool {
+    Op::from_instruction(instruction).is_some()
+        && instruction.operands() == [&Operand::Pop, &Operand::Pop]
+        && instruction.target() == Some(&Target::Push)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{golden, vm::Vm};
+
+    fn disassemble(source: &str) -> Vec<String> {
+        let (program, _) = fuse(source.parse::<Program>().unwrap());
+        program.0.iter().map(Instruction::to_string).collect()
+    }
+
+    #[test]
+    fn test_operate_and_push() {
+        assert_eq!(
+            disassemble("mov push _n_\nmov push 1\n- pop pop push"),
+            vec!["- 1 _n_ push"]
+        );
+    }
+
+    #[test]
+    fn test_compare_and_branch() {
+        assert_eq!(
+            disassemble("mov push _num_\nmov push 2\n< pop pop push\njf $$Else$$ pop"),
+            vec!["jf.< $$Else$$ 2 _num_"]
+        );
+    }
+
+    #[test]
+    fn test_stack_operands_are_not_fused() {
+        assert_eq!(
+            disassemble("mov push 2\n< pop _i_ push\njf $$Exit$$ pop"),
+            vec!["mov push 2", "< pop _i_ push", "jf $$Exit$$ pop"]
+        );
+    }
+
+    #[test]
+    fn test_origin() {
+        let program = "mov push 1\nmov push _n_\nmov push 1\n- pop pop push\nout"
+            .parse::<Program>()
+            .unwrap();
+
+        assert_eq!(fuse(program).1, vec![0, 1, 4]);
+    }
+
+    #[test]
+    fn test_programs_keep_results() {
+        golden::assert_results(|program, input| Vm::with_input(input).run(&fuse(program).0));
+    }
+
+    #[test]
+    fn test_programs_shrink() {
+        let program = include_str!("../test/fibonacci.4km")
+            .parse::<Program>()
+            .unwrap();
+        let len = program.0.len();
+
+        let (fused, _) = fuse(program);
+
+        assert!(fused.0.len() < len);
+    }
+}
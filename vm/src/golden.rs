@@ -0,0 +1,273 @@
+use crate::{fuse, input::Lines, output::Buffer, program::Program, vm::Vm};
+#[cfg(test)]
+use crate::{input::Input, value::Value};
+use std::{
+    fmt::Write,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// Bounds on each test run, so that a runaway program fails instead of
+/// hanging the suite.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            instructions: 100_000_000,
+            time: Duration::from_secs(10),
+        }
+    }
+}
+
+/// How often the clock is checked, in instructions.
+static CLOCK_INTERVAL: u64 = 4096;
+
+/// A `.4km` program and its fixtures: `<name>.result` holds the returned
+/// value as a literal or `Error: <message>`, `<name>.stdout` what it prints,
+/// if anything, and `<name>.stdin` the lines `read` returns.
+#[derive(Debug)]
+pub struct Case {
+    pub program: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub result: String,
+    pub stdout: String,
+}
+
+impl Case {
+    /// The programs in `dir`, sorted by name.
+    pub fn discover(dir: &Path) -> Result<Vec<Self>, String> {
+        let entries =
+            fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+
+        let mut programs = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "4km"))
+            .collect::<Vec<_>>();
+        programs.sort();
+
+        Ok(programs
+            .into_iter()
+            .map(|program| Self { program })
+            .collect())
+    }
+
+    pub fn name(&self) -> String {
+        self.program
+            .file_stem()
+            .map_or(String::new(), |stem| stem.to_string_lossy().to_string())
+    }
+
+    fn fixture(&self, extension: &str) -> PathBuf {
+        self.program.with_extension(extension)
+    }
+
+    fn read_fixture(&self, extension: &str) -> Result<Option<String>, String> {
+        let path = self.fixture(extension);
+        match fs::read_to_string(&path) {
+            Ok(text) => Ok(Some(text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    pub fn run(&self, limits: Limits) -> Result<Outcome, String> {
+        let source = fs::read_to_string(&self.program)
+            .map_err(|e| format!("Failed to read {}: {}", self.program.display(), e))?;
+        let stdin = self.read_fixture("stdin")?.unwrap_or_default();
+
+        let output = Buffer::default();
+        let result = source
+            .parse::<Program>()
+            .and_then(|program| execute(&fuse::fuse(program).0, &stdin, &output, limits));
+
+        Ok(Outcome {
+            result: match result {
+                Ok(value) => value,
+                Err(e) => format!("Error: {}", e),
+            },
+            stdout: output.take(),
+        })
+    }
+
+    /// Describes each way `outcome` differs from the fixtures.
+    pub fn check(&self, outcome: &Outcome) -> Result<Vec<String>, String> {
+        let mut mismatches = vec![];
+
+        match self.read_fixture("result")? {
+            Some(expected) if expected.trim_end_matches('\n') == outcome.result => {}
+            Some(expected) => mismatches.push(format!(
+                "result differs:\n{}",
+                diff(expected.trim_end_matches('\n'), &outcome.result)
+            )),
+            None => mismatches.push(format!(
+                "{} is missing, the result was {}",
+                self.fixture("result").display(),
+                outcome.result
+            )),
+        }
+
+        let expected = self.read_fixture("stdout")?.unwrap_or_default();
+        if expected != outcome.stdout {
+            mismatches.push(format!(
+                "stdout differs:\n{}",
+                diff(&expected, &outcome.stdout)
+            ));
+        }
+
+        Ok(mismatches)
+    }
+
+    /// Makes the fixtures expect `outcome`.
+    pub fn bless(&self, outcome: &Outcome) -> Result<(), String> {
+        let write = |path: PathBuf, text: &str| {
+            fs::write(&path, text).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+        };
+
+        write(self.fixture("result"), &format!("{}\n", outcome.result))?;
+
+        let stdout = self.fixture("stdout");
+        if !outcome.stdout.is_empty() {
+            write(stdout, &outcome.stdout)?;
+        } else if stdout.exists() {
+            fs::remove_file(&stdout)
+                .map_err(|e| format!("Failed to remove {}: {}", stdout.display(), e))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Asserts that `run` gives each program in `test/` the result its fixture
+/// expects, for checking transformations and other engines against them.
+#[cfg(test)]
+pub fn assert_results(run: impl Fn(Program, Box<dyn Input>) -> Result<Value, String>) {
+    for case in Case::discover(Path::new("test")).unwrap() {
+        let source = fs::read_to_string(&case.program).unwrap();
+        let stdin = case.read_fixture("stdin").unwrap().unwrap_or_default();
+
+        let result = match run(source.parse().unwrap(), Box::new(Lines::new(&stdin))) {
+            Ok(value) => value.literal(),
+            Err(e) => format!("Error: {}", e),
+        };
+        let expected = case.read_fixture("result").unwrap().unwrap();
+        assert_eq!(result, expected.trim_end_matches('\n'), "{}", case.name());
+    }
+}
+
+/// Runs `program` and returns its result as a literal.
+fn execute(
+    program: &Program,
+    stdin: &str,
+    output: &Buffer,
+    limits: Limits,
+) -> Result<String, String> {
+    let mut vm = Vm::with_io(Box::new(Lines::new(stdin)), Box::new(output.clone()));
+    let start = Instant::now();
+
+    for count in 1.. {
+        if let Some(value) = vm.step(program, &mut ())? {
+            return Ok(value.literal());
+        }
+
+        if count >= limits.instructions {
+            return Err(format!(
+                "Exceeded the limit of {} instructions",
+                limits.instructions
+            ));
+        }
+        if count % CLOCK_INTERVAL == 0 && start.elapsed() > limits.time {
+            return Err(format!("Exceeded the time limit of {:?}", limits.time));
+        }
+    }
+
+    unreachable!()
+}
+
+/// A line diff of `expected` and `actual`, marking missing lines with `-`
+/// and unexpected ones with `+`.
+pub fn diff(expected: &str, actual: &str) -> String {
+    let a = expected.lines().collect::<Vec<_>>();
+    let b = actual.lines().collect::<Vec<_>>();
+
+    // lcs[i][j] is the length of the longest common subsequence of a[i..]
+    // and b[j..].
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            writeln!(out, "  {}", a[i]).unwrap();
+            (i, j) = (i + 1, j + 1);
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            writeln!(out, "- {}", a[i]).unwrap();
+            i += 1;
+        } else {
+            writeln!(out, "+ {}", b[j]).unwrap();
+            j += 1;
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixtures() {
+        let cases = Case::discover(Path::new("test")).unwrap();
+        assert!(cases.len() >= 7);
+
+        for case in cases {
+            let outcome = case.run(Limits::default()).unwrap();
+            assert_eq!(
+                case.check(&outcome).unwrap(),
+                Vec::<String>::new(),
+                "{}",
+                case.name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        let program = "lbl $$Function__main_$$\nlbl $$Loop$$\njmp $$Loop$$"
+            .parse::<Program>()
+            .unwrap();
+        let limits = Limits {
+            instructions: 100,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            execute(&program, "", &Buffer::default(), limits),
+            Err("Exceeded the limit of 100 instructions".to_string())
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff("a\nb\nc", "a\nc\nd"), "  a\n- b\n  c\n+ d\n");
+        assert_eq!(diff("", "x"), "+ x\n");
+    }
+}
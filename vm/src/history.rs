@@ -0,0 +1,327 @@
+use crate::{
+    instruction::Instruction,
+    program::Program,
+    target::Target,
+    value::Value,
+    vm::{Frame, Scope, Vm},
+};
+use std::collections::VecDeque;
+
+/// A variable assignment, with the value it replaced.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Write {
+    pub name: String,
+    pub old: Option<Value>,
+    pub new: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    /// The frame pushed by a `call`, which also opened an empty scope.
+    Call(Frame),
+    /// A block opened by `in`.
+    Enter,
+    /// A block closed by `out`, and the enclosing scope as it was before the
+    /// block's assignments were copied into it.
+    Leave(Scope, Scope),
+    /// The frame closed by `out` or `ret`, and its scopes, innermost last.
+    /// Returning from main keeps its frame.
+    Return(Option<Frame>, Vec<Scope>),
+}
+
+/// What one executed instruction changed, enough to undo and redo it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta {
+    /// Index of the instruction.
+    pub ip: usize,
+    /// Index of the instruction that ran next.
+    pub next: usize,
+    /// Call depth the instruction ran at.
+    pub depth: usize,
+    /// Values taken from the top of the stack, bottom first.
+    pub popped: Vec<Value>,
+    /// Values left on top of the stack in their place.
+    pub pushed: Vec<Value>,
+    pub write: Option<Write>,
+    pub control: Option<Control>,
+    /// The result of the run if the instruction ended it.
+    pub result: Option<Value>,
+}
+
+/// The most recent deltas of a run, and the ones undone since.
+#[derive(Debug)]
+pub struct History {
+    past: VecDeque<Delta>,
+    future: Vec<Delta>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            past: VecDeque::new(),
+            future: vec![],
+            capacity,
+        }
+    }
+
+    /// Executes the next instruction, or redoes it after stepping back.
+    pub fn step(&mut self, vm: &mut Vm, program: &Program) -> Result<Option<Value>, String> {
+        if let Some(delta) = self.future.pop() {
+            vm.redo(&delta);
+            let result = delta.result.clone();
+            self.push(delta);
+            return Ok(result);
+        }
+
+        if !vm.is_started() {
+            return vm.step(program, &mut ());
+        }
+
+        let delta = record(vm, program)?;
+        let result = delta.result.clone();
+        self.push(delta);
+        Ok(result)
+    }
+
+    /// Undoes the last instruction, unless the history is exhausted.
+    pub fn back(&mut self, vm: &mut Vm) -> bool {
+        let Some(delta) = self.past.pop_back() else {
+            return false;
+        };
+
+        vm.undo(&delta);
+        self.future.push(delta);
+        true
+    }
+
+    /// Number of steps back to the last write to `name` in the frame at
+    /// `depth`, if it is still in the history.
+    pub fn last_write(&self, name: &str, depth: usize) -> Option<usize> {
+        for (steps, delta) in self.past.iter().rev().enumerate() {
+            // Older deltas belong to the frame's caller, or a previous call.
+            if delta.depth < depth {
+                return None;
+            }
+
+            let written = delta.write.as_ref().is_some_and(|write| write.name == name);
+            if delta.depth == depth && written {
+                return Some(steps + 1);
+            }
+        }
+
+        None
+    }
+
+    fn push(&mut self, delta: Delta) {
+        if self.past.len() == self.capacity {
+            self.past.pop_front();
+        }
+        if self.capacity > 0 {
+            self.past.push_back(delta);
+        }
+    }
+}
+
+/// Executes the next instruction of a started VM. A failing instruction is
+/// undone, so that the VM stays before it.
+fn record(vm: &mut Vm, program: &Program) -> Result<Delta, String> {
+    let ip = vm.ip();
+    let depth = vm.depth();
+    let instruction = program
+        .0
+        .get(ip)
+        .ok_or_else(|| format!("No instruction found at index {}", ip))?;
+
+    let leaving = matches!(instruction, Instruction::ScopeOut(_)) && vm.in_block();
+    let returning = !leaving && matches!(instruction, Instruction::ScopeOut(_) | Instruction::Ret);
+    let (mut pops, _) = instruction.stack_effect();
+    // Returning from main pops the result.
+    if returning && depth == 1 {
+        pops += 1;
+    }
+
+    let base = vm.stack().len().saturating_sub(pops);
+    let popped = vm.stack()[base..].to_vec();
+    let name = match instruction.target() {
+        Some(Target::Id(name)) => Some(name.clone()),
+        _ => None,
+    };
+    let old = name.as_ref().and_then(|name| vm.variable(name).cloned());
+    let scopes = vm.scopes();
+    let left = leaving.then(|| {
+        Control::Leave(
+            scopes[scopes.len() - 1].clone(),
+            scopes[scopes.len() - 2].clone(),
+        )
+    });
+    let returned = returning.then(|| {
+        let frame = vm.frames().last().cloned();
+        let scope = frame.as_ref().map_or(0, |frame| frame.scope);
+        (frame, scopes.get(scope..).unwrap_or_default().to_vec())
+    });
+
+    let result = vm.step(program, &mut ());
+
+    let control = match instruction {
+        Instruction::Call(_) if vm.depth() > depth => {
+            vm.frames().last().cloned().map(Control::Call)
+        }
+        Instruction::ScopeIn(_) => Some(Control::Enter),
+        _ if leaving => left,
+        _ => returned
+            .map(|(frame, scopes)| Control::Return(frame.filter(|_| vm.depth() < depth), scopes)),
+    };
+    let write = match (&result, name) {
+        (Ok(_), Some(name)) => vm
+            .variable(&name)
+            .cloned()
+            .map(|new| Write { name, old, new }),
+        _ => None,
+    };
+
+    let delta = Delta {
+        ip,
+        next: vm.ip(),
+        depth,
+        popped,
+        pushed: vm.stack().get(base..).unwrap_or_default().to_vec(),
+        write,
+        control,
+        result: None,
+    };
+
+    match result {
+        Ok(result) => Ok(Delta { result, ..delta }),
+        Err(e) => {
+            vm.undo(&delta);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{label::Label, vm::MAIN_FN};
+
+    fn program() -> Program {
+        include_str!("../test/factorial.4km")
+            .parse::<Program>()
+            .unwrap()
+    }
+
+    fn start(program: &Program) -> Vm {
+        let mut vm = Vm::default();
+        vm.enter(program, &Label::function(MAIN_FN), vec![])
+            .unwrap();
+        vm
+    }
+
+    /// Runs `program` to the end, undoes every step and redoes them again.
+    fn undo_to_start_and_redo(program: &Program) {
+        let mut vm = start(program);
+        let start = vm.snapshot(program);
+        let mut history = History::new(usize::MAX);
+
+        let mut steps = 0;
+        let result = loop {
+            steps += 1;
+            if let Some(result) = history.step(&mut vm, program).unwrap() {
+                break result;
+            }
+        };
+        let end = vm.snapshot(program);
+
+        for _ in 0..steps {
+            assert!(history.back(&mut vm));
+        }
+        assert!(!history.back(&mut vm));
+        assert_eq!(vm.snapshot(program), start);
+
+        for _ in 1..steps {
+            assert_eq!(history.step(&mut vm, program).unwrap(), None);
+        }
+        assert_eq!(history.step(&mut vm, program).unwrap(), Some(result));
+        assert_eq!(vm.snapshot(program), end);
+    }
+
+    #[test]
+    fn test_undo_to_start_and_redo() {
+        undo_to_start_and_redo(&program());
+    }
+
+    #[test]
+    fn test_undo_blocks() {
+        let program = r#"
+            lbl $$Function__f_$$
+            pop _n_
+            in
+            + _n_ 1 _n_
+            in
+            mov _x_ 1
+            push _n_
+            ret
+            lbl $$Function__main_$$
+            mov _n_ 10
+            in
+            push 4
+            call $$Function__f_$$
+            pop _r_
+            + _r_ _n_ _n_
+            out
+            push _n_
+            out
+        "#
+        .parse::<Program>()
+        .unwrap();
+
+        undo_to_start_and_redo(&program);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let program = program();
+        let mut vm = start(&program);
+        let mut history = History::new(3);
+
+        for _ in 0..10 {
+            history.step(&mut vm, &program).unwrap();
+        }
+
+        assert_eq!((0..5).filter(|_| history.back(&mut vm)).count(), 3);
+    }
+
+    #[test]
+    fn test_failed_instruction_is_undone() {
+        let program = "lbl $$Function__main_$$\nmov push 1\nmov push \"a\"\n- pop pop push\nout"
+            .parse::<Program>()
+            .unwrap();
+        let mut vm = start(&program);
+        let mut history = History::new(10);
+
+        history.step(&mut vm, &program).unwrap();
+        history.step(&mut vm, &program).unwrap();
+        history.step(&mut vm, &program).unwrap();
+        let before = vm.snapshot(&program);
+
+        assert!(history.step(&mut vm, &program).is_err());
+        assert_eq!(vm.snapshot(&program), before);
+    }
+
+    #[test]
+    fn test_last_write() {
+        let program = "lbl $$Function__main_$$\nmov _x_ 1\nmov _y_ 2\nmov _x_ 3\nmov _y_ 4\nout"
+            .parse::<Program>()
+            .unwrap();
+        let mut vm = start(&program);
+        let mut history = History::new(10);
+        for _ in 0..5 {
+            history.step(&mut vm, &program).unwrap();
+        }
+
+        assert_eq!(history.last_write("_x_", vm.depth()), Some(2));
+        assert_eq!(history.last_write("_y_", vm.depth()), Some(1));
+        assert_eq!(history.last_write("_z_", vm.depth()), None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io::{self, Write},
+};
+
+/// Where `read` takes its lines from.
+pub trait Input: Debug {
+    /// The next line, without its line break.
+    fn read_line(&mut self) -> Result<String, String>;
+}
+
+impl Default for Box<dyn Input> {
+    fn default() -> Self {
+        Box::new(Stdin)
+    }
+}
+
+#[derive(Debug)]
+pub struct Stdin;
+
+impl Input for Stdin {
+    fn read_line(&mut self) -> Result<String, String> {
+        let mut line = String::new();
+        io::stdin()
+            .read_line(&mut line)
+            .map_err(|e| format!("Failed to read input: {e}"))?;
+
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// Feeds the lines of a text, such as a test's stdin fixture.
+#[derive(Debug)]
+pub struct Lines(VecDeque<String>);
+
+impl Lines {
+    pub fn new(text: &str) -> Self {
+        Self(text.lines().map(str::to_string).collect())
+    }
+}
+
+impl Input for Lines {
+    fn read_line(&mut self) -> Result<String, String> {
+        self.0
+            .pop_front()
+            .ok_or_else(|| "Read past the end of the input".to_string())
+    }
+}
+
+/// Passes lines through from `input` and logs them to `out`, after a header
+/// with the fingerprint of the program.
+#[derive(Debug)]
+pub struct Recorder<I: Input, W: Write + Debug> {
+    input: I,
+    out: W,
+}
+
+impl<I: Input, W: Write + Debug> Recorder<I, W> {
+    pub fn new(input: I, mut out: W, fingerprint: u64) -> Result<Self, String> {
+        writeln!(out, "program {:016x}", fingerprint)
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("Failed to write recording: {}", e))?;
+
+        Ok(Self { input, out })
+    }
+}
+
+impl<I: Input, W: Write + Debug> Input for Recorder<I, W> {
+    fn read_line(&mut self) -> Result<String, String> {
+        let line = self.input.read_line()?;
+
+        writeln!(self.out, "{}", line)
+            .and_then(|_| self.out.flush())
+            .map_err(|e| format!("Failed to write recording: {}", e))?;
+
+        Ok(line)
+    }
+}
+
+/// Feeds back the lines of a recording.
+#[derive(Debug)]
+pub struct Replay {
+    lines: VecDeque<String>,
+    total: usize,
+}
+
+impl Replay {
+    /// Parses a recording, failing if it was made for another program.
+    pub fn parse(recording: &str, fingerprint: u64) -> Result<Self, String> {
+        let mut lines = recording.lines();
+        let expected = format!("program {:016x}", fingerprint);
+
+        match lines.next() {
+            Some(header) if header == expected => {}
+            Some(header) if header.starts_with("program ") => {
+                return Err(format!(
+                    "The recording was made with a different program ({}, now {:016x})",
+                    &header["program ".len()..],
+                    fingerprint
+                ))
+            }
+            _ => return Err("Invalid recording: missing program header".to_string()),
+        }
+
+        let lines = lines.map(str::to_string).collect::<VecDeque<_>>();
+
+        Ok(Self {
+            total: lines.len(),
+            lines,
+        })
+    }
+}
+
+impl Input for Replay {
+    fn read_line(&mut self) -> Result<String, String> {
+        self.lines.pop_front().ok_or_else(|| {
+            format!(
+                "Read past the end of the recording after {} inputs",
+                self.total
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_replay() {
+        let mut recorder = Recorder::new(Lines::new("5\n\"x\""), vec![], 42).unwrap();
+        assert_eq!(recorder.read_line().unwrap(), "5");
+        assert_eq!(recorder.read_line().unwrap(), "\"x\"");
+
+        let recording = String::from_utf8(recorder.out).unwrap();
+        assert_eq!(recording, "program 000000000000002a\n5\n\"x\"\n");
+
+        let mut replay = Replay::parse(&recording, 42).unwrap();
+        assert_eq!(replay.read_line().unwrap(), "5");
+        assert_eq!(replay.read_line().unwrap(), "\"x\"");
+        assert_eq!(
+            replay.read_line().unwrap_err(),
+            "Read past the end of the recording after 2 inputs"
+        );
+    }
+
+    #[test]
+    fn test_replay_rejects_other_program() {
+        assert_eq!(
+            Replay::parse("program 000000000000002a\n5\n", 7).unwrap_err(),
+            "The recording was made with a different program (000000000000002a, now 0000000000000007)"
+        );
+        assert!(Replay::parse("5\n", 7).is_err());
+    }
+}
@@ -1,5 +1,5 @@
-use crate::{label::Label, operand::Operand, target::Target};
-use std::str::FromStr;
+use crate::{label::Label, op::Op, operand::Operand, target::Target, value::Value};
+use std::{fmt::Display, str::FromStr};
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Instruction {
@@ -23,12 +23,27 @@ pub enum Instruction {
 
     Jmp(Label),
     JmpFalse(Label, Operand),
+    /// `<op> a b push` followed by `jf <label> pop`, fused by the loader.
+    JmpFalseOp(Label, Op, Operand, Operand),
+
+    Push(Operand),
+    Pop(Target),
 
     Print(Operand),
     Read,
     Call(Label),
 
-    ScopeOut,
+    /// Opens a block scope that sees the variables of the enclosing one. The
+    /// compiler names each block with a `$$Scope_<n>$$` label, which is only
+    /// kept for display.
+    ScopeIn(Option<Label>),
+    /// Leaves the innermost scope: closes the block opened by the matching
+    /// `in`, or returns from the function when only its own scope is left.
+    ScopeOut(Option<Label>),
+    /// Returns from the function, closing any blocks it left open.
+    Ret,
+    /// Ends the run from any call depth, with the operand as its result.
+    Halt(Operand),
 
     Label(Label),
 }
@@ -170,13 +185,38 @@ impl FromStr for Instruction {
                 Self::JmpFalse(label.parse::<Label>()?, operand.parse::<Operand>()?)
             }
 
+            (fused, Some(label), Some(operand1), Some(operand2))
+                if fused.starts_with("jf.") && Op::from_mnemonic(&fused[3..]).is_some() =>
+            {
+                Self::JmpFalseOp(
+                    label.parse::<Label>()?,
+                    Op::from_mnemonic(&fused[3..]).unwrap(),
+                    operand1.parse::<Operand>()?,
+                    operand2.parse::<Operand>()?,
+                )
+            }
+
+            ("push", Some(operand), None, None) => Self::Push(operand.parse::<Operand>()?),
+
+            ("pop", Some(target), None, None) => Self::Pop(target.parse::<Target>()?),
+
             ("read", None, None, None) => Self::Read,
 
+            ("in", None, None, None) => Self::ScopeIn(None),
+
+            ("in", Some(label), None, None) => Self::ScopeIn(Some(label.parse::<Label>()?)),
+
             ("prn", Some(operand), None, None) => Self::Print(operand.parse::<Operand>()?),
 
             ("call", Some(label), None, None) => Self::Call(label.parse::<Label>()?),
 
-            ("out", None, None, None) => Self::ScopeOut,
+            ("out", None, None, None) => Self::ScopeOut(None),
+
+            ("out", Some(label), None, None) => Self::ScopeOut(Some(label.parse::<Label>()?)),
+
+            ("ret", None, None, None) => Self::Ret,
+
+            ("halt", Some(operand), None, None) => Self::Halt(operand.parse::<Operand>()?),
 
             ("lbl", Some(label), None, None) => Self::Label(label.parse::<Label>()?),
 
@@ -187,6 +227,274 @@ impl FromStr for Instruction {
     }
 }
 
+impl Instruction {
+    /// Computes the result of an operator from its evaluated operands.
+    pub fn evaluate(&self, values: &[Value]) -> Result<Value, String> {
+        let op = match self {
+            Self::JmpFalseOp(_, op, _, _) => Some(*op),
+            _ => Op::from_instruction(self),
+        };
+
+        op.ok_or_else(|| format!("Cannot evaluate {}", self))?
+            .evaluate(values)
+    }
+
+    /// The opcode as written in source, e.g. `mov` or `jf.<`.
+    pub fn mnemonic(&self) -> String {
+        use Instruction::*;
+
+        match self {
+            Mov(..) => "mov".to_string(),
+            Jmp(_) => "jmp".to_string(),
+            JmpFalse(..) => "jf".to_string(),
+            JmpFalseOp(_, op, _, _) => format!("jf.{}", op),
+            Push(_) => "push".to_string(),
+            Pop(_) => "pop".to_string(),
+            Print(_) => "prn".to_string(),
+            Read => "read".to_string(),
+            ScopeIn(_) => "in".to_string(),
+            Call(_) => "call".to_string(),
+            ScopeOut(_) => "out".to_string(),
+            Ret => "ret".to_string(),
+            Halt(_) => "halt".to_string(),
+            Label(_) => "lbl".to_string(),
+            _ => Op::from_instruction(self).unwrap().mnemonic().to_string(),
+        }
+    }
+
+    /// Every opcode that can be written in source, except fused `jf.<op>`.
+    pub fn mnemonics() -> Vec<&'static str> {
+        let mut mnemonics = vec![
+            "mov", "jmp", "jf", "push", "pop", "prn", "read", "in", "call", "out", "ret", "halt",
+            "lbl",
+        ];
+        mnemonics.extend(Op::ALL.map(Op::mnemonic));
+        mnemonics
+    }
+
+    /// Number of values popped from and pushed onto the value stack, not
+    /// counting the result that `out` pops when main returns.
+    pub fn stack_effect(&self) -> (usize, usize) {
+        let pops = self
+            .operands()
+            .iter()
+            .filter(|operand| matches!(operand, Operand::Pop))
+            .count()
+            + matches!(self, Self::Pop(_)) as usize;
+        let pushes = match self {
+            Self::Read | Self::Push(_) => 1,
+            _ => matches!(self.target(), Some(Target::Push)) as usize,
+        };
+
+        (pops, pushes)
+    }
+
+    /// The label jumped to, called or defined.
+    pub fn label(&self) -> Option<&Label> {
+        use Instruction::*;
+
+        match self {
+            Jmp(label)
+            | JmpFalse(label, _)
+            | JmpFalseOp(label, ..)
+            | Call(label)
+            | Label(label) => Some(label),
+            _ => None,
+        }
+    }
+
+    /// Operands in the order `Vm::run_instruction` evaluates them.
+    pub fn operands(&self) -> Vec<&Operand> {
+        use Instruction::*;
+
+        match self {
+            Mov(_, operand) | Not(operand, _) | Neg(operand, _) => vec![operand],
+            Add(operand1, operand2, _)
+            | Sub(operand1, operand2, _)
+            | Mul(operand1, operand2, _)
+            | Div(operand1, operand2, _)
+            | Mod(operand1, operand2, _)
+            | And(operand1, operand2, _)
+            | Or(operand1, operand2, _)
+            | Eq(operand1, operand2, _)
+            | Neq(operand1, operand2, _)
+            | Less(operand1, operand2, _)
+            | LessEq(operand1, operand2, _)
+            | Greater(operand1, operand2, _)
+            | GreaterEq(operand1, operand2, _)
+            | JmpFalseOp(_, _, operand1, operand2) => vec![operand1, operand2],
+            JmpFalse(_, operand) | Push(operand) | Print(operand) | Halt(operand) => {
+                vec![operand]
+            }
+            Jmp(_) | Pop(_) | Read | Call(_) | ScopeIn(_) | ScopeOut(_) | Ret | Label(_) => vec![],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        use Instruction::*;
+
+        match self {
+            Mov(_, operand) | Not(operand, _) | Neg(operand, _) => vec![operand],
+            Add(operand1, operand2, _)
+            | Sub(operand1, operand2, _)
+            | Mul(operand1, operand2, _)
+            | Div(operand1, operand2, _)
+            | Mod(operand1, operand2, _)
+            | And(operand1, operand2, _)
+            | Or(operand1, operand2, _)
+            | Eq(operand1, operand2, _)
+            | Neq(operand1, operand2, _)
+            | Less(operand1, operand2, _)
+            | LessEq(operand1, operand2, _)
+            | Greater(operand1, operand2, _)
+            | GreaterEq(operand1, operand2, _)
+            | JmpFalseOp(_, _, operand1, operand2) => vec![operand1, operand2],
+            JmpFalse(_, operand) | Push(operand) | Print(operand) | Halt(operand) => {
+                vec![operand]
+            }
+            Jmp(_) | Pop(_) | Read | Call(_) | ScopeIn(_) | ScopeOut(_) | Ret | Label(_) => vec![],
+        }
+    }
+
+    pub fn target(&self) -> Option<&Target> {
+        use Instruction::*;
+
+        match self {
+            Mov(target, _)
+            | Not(_, target)
+            | Neg(_, target)
+            | Add(_, _, target)
+            | Sub(_, _, target)
+            | Mul(_, _, target)
+            | Div(_, _, target)
+            | Mod(_, _, target)
+            | And(_, _, target)
+            | Or(_, _, target)
+            | Eq(_, _, target)
+            | Neq(_, _, target)
+            | Less(_, _, target)
+            | LessEq(_, _, target)
+            | Greater(_, _, target)
+            | GreaterEq(_, _, target)
+            | Pop(target) => Some(target),
+            Jmp(_)
+            | JmpFalse(_, _)
+            | JmpFalseOp(..)
+            | Push(_)
+            | Print(_)
+            | Read
+            | Call(_)
+            | ScopeIn(_)
+            | ScopeOut(_)
+            | Ret
+            | Halt(_)
+            | Label(_) => None,
+        }
+    }
+
+    pub fn target_mut(&mut self) -> Option<&mut Target> {
+        use Instruction::*;
+
+        match self {
+            Mov(target, _)
+            | Not(_, target)
+            | Neg(_, target)
+            | Add(_, _, target)
+            | Sub(_, _, target)
+            | Mul(_, _, target)
+            | Div(_, _, target)
+            | Mod(_, _, target)
+            | And(_, _, target)
+            | Or(_, _, target)
+            | Eq(_, _, target)
+            | Neq(_, _, target)
+            | Less(_, _, target)
+            | LessEq(_, _, target)
+            | Greater(_, _, target)
+            | GreaterEq(_, _, target)
+            | Pop(target) => Some(target),
+            Jmp(_)
+            | JmpFalse(_, _)
+            | JmpFalseOp(..)
+            | Push(_)
+            | Print(_)
+            | Read
+            | Call(_)
+            | ScopeIn(_)
+            | ScopeOut(_)
+            | Ret
+            | Halt(_)
+            | Label(_) => None,
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mov(target, operand) => write!(f, "mov {} {}", target, operand),
+            Self::Add(operand1, operand2, target) => {
+                write!(f, "+ {} {} {}", operand1, operand2, target)
+            }
+            Self::Sub(operand1, operand2, target) => {
+                write!(f, "- {} {} {}", operand1, operand2, target)
+            }
+            Self::Mul(operand1, operand2, target) => {
+                write!(f, "* {} {} {}", operand1, operand2, target)
+            }
+            Self::Div(operand1, operand2, target) => {
+                write!(f, "/ {} {} {}", operand1, operand2, target)
+            }
+            Self::Mod(operand1, operand2, target) => {
+                write!(f, "% {} {} {}", operand1, operand2, target)
+            }
+            Self::And(operand1, operand2, target) => {
+                write!(f, "& {} {} {}", operand1, operand2, target)
+            }
+            Self::Or(operand1, operand2, target) => {
+                write!(f, "| {} {} {}", operand1, operand2, target)
+            }
+            Self::Not(operand, target) => write!(f, "! {} {}", operand, target),
+            Self::Neg(operand, target) => write!(f, "neg {} {}", operand, target),
+            Self::Eq(operand1, operand2, target) => {
+                write!(f, "== {} {} {}", operand1, operand2, target)
+            }
+            Self::Neq(operand1, operand2, target) => {
+                write!(f, "!= {} {} {}", operand1, operand2, target)
+            }
+            Self::Less(operand1, operand2, target) => {
+                write!(f, "< {} {} {}", operand1, operand2, target)
+            }
+            Self::LessEq(operand1, operand2, target) => {
+                write!(f, "<= {} {} {}", operand1, operand2, target)
+            }
+            Self::Greater(operand1, operand2, target) => {
+                write!(f, "> {} {} {}", operand1, operand2, target)
+            }
+            Self::GreaterEq(operand1, operand2, target) => {
+                write!(f, ">= {} {} {}", operand1, operand2, target)
+            }
+            Self::Jmp(label) => write!(f, "jmp {}", label),
+            Self::JmpFalse(label, operand) => write!(f, "jf {} {}", label, operand),
+            Self::JmpFalseOp(label, op, operand1, operand2) => {
+                write!(f, "jf.{} {} {} {}", op, label, operand1, operand2)
+            }
+            Self::Push(operand) => write!(f, "push {}", operand),
+            Self::Pop(target) => write!(f, "pop {}", target),
+            Self::Print(operand) => write!(f, "prn {}", operand),
+            Self::Read => write!(f, "read"),
+            Self::ScopeIn(None) => write!(f, "in"),
+            Self::ScopeIn(Some(label)) => write!(f, "in {}", label),
+            Self::Call(label) => write!(f, "call {}", label),
+            Self::ScopeOut(None) => write!(f, "out"),
+            Self::ScopeOut(Some(label)) => write!(f, "out {}", label),
+            Self::Ret => write!(f, "ret"),
+            Self::Halt(operand) => write!(f, "halt {}", operand),
+            Self::Label(label) => write!(f, "lbl {}", label),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::value::Value;
@@ -199,7 +507,7 @@ mod tests {
             r#"mov push "Enter X""#.parse::<Instruction>(),
             Ok(Instruction::Mov(
                 Target::Push,
-                Operand::Value(Value::String("Enter X".to_string()))
+                Operand::Value(Value::String("Enter X".into()))
             ))
         );
     }
@@ -211,4 +519,91 @@ mod tests {
             Ok(Instruction::Label(Label::new("$$Function__main_$$")))
         );
     }
+
+    #[test]
+    fn test_stack_effect() {
+        let effect = |s: &str| s.parse::<Instruction>().unwrap().stack_effect();
+
+        assert_eq!(effect("+ pop pop push"), (2, 1));
+        assert_eq!(effect("mov _x_ pop"), (1, 0));
+        assert_eq!(effect("read"), (0, 1));
+        assert_eq!(effect("jf $$End$$ _x_"), (0, 0));
+        assert_eq!(effect("halt pop"), (1, 0));
+        assert_eq!(effect("push 1"), (0, 1));
+        assert_eq!(effect("pop _x_"), (1, 0));
+        assert_eq!(effect("in"), (0, 0));
+    }
+
+    #[test]
+    fn test_mnemonics_match_compiler() {
+        // e.g. `Mov = "mov", // mov <dst> <src>` in the compiler's enum, where
+        // each `<...>` in the comment is an operand.
+        let mut compiler = include_str!("../../src/asm/instructions.ts")
+            .lines()
+            .filter_map(|line| line.split_once(" = \""))
+            .map(|(_, rest)| {
+                let (mnemonic, comment) = rest.split_once('"').unwrap();
+                (mnemonic, vec![comment.matches('<').count()])
+            })
+            .collect::<Vec<_>>();
+        for (mnemonic, arities) in &mut compiler {
+            match *mnemonic {
+                // The compiler emits `read` bare, and the VM pushes the line.
+                "read" => *arities = vec![0],
+                // Blocks are named by a label, which hand-written programs
+                // may leave out.
+                "in" | "out" => *arities = vec![0, 1],
+                _ => {}
+            }
+        }
+
+        // The operand counts the VM parses, trying a label and a variable in
+        // each position.
+        let arities = |mnemonic: &str| {
+            (0..=3)
+                .filter(|&count| {
+                    (0..1 << count).any(|choice| {
+                        let operands = (0..count)
+                            .map(|i| if choice >> i & 1 == 0 { "_x_" } else { "$$L$$" })
+                            .collect::<Vec<_>>();
+                        format!("{} {}", mnemonic, operands.join(" "))
+                            .parse::<Instruction>()
+                            .is_ok()
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        // The compiler does not emit `halt`; only programs written by hand use it.
+        let mut vm = Instruction::mnemonics()
+            .into_iter()
+            .filter(|mnemonic| *mnemonic != "halt")
+            .map(|mnemonic| (mnemonic, arities(mnemonic)))
+            .collect::<Vec<_>>();
+        compiler.sort();
+        vm.sort();
+
+        assert_eq!(vm, compiler);
+    }
+
+    #[test]
+    fn test_display_roundtrip() {
+        for line in [
+            r#"mov push "Enter X""#,
+            "+ pop _i_ push",
+            "jf $$Exit_Loop_1320$$ pop",
+            "call $$Function__fib_$$",
+            "jf.< $$Exit_Loop_1$$ 28 _i_",
+            "out",
+            "halt pop",
+            "push _x_",
+            "pop push",
+            "in",
+            "in $$Scope_1$$",
+            "out $$Scope_1$$",
+            "ret",
+        ] {
+            let instruction = line.parse::<Instruction>().unwrap();
+            assert_eq!(instruction.to_string(), line);
+        }
+    }
 }
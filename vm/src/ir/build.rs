@@ -0,0 +1,439 @@
+use super::{Arg, Block, BlockId, Function, Inst, Phi, Terminator};
+use crate::{
+    instruction::Instruction, label::Label, op::Op, operand::Operand, program, program::Program,
+    target::Target,
+};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Default)]
+struct State {
+    vars: HashMap<String, Arg>,
+    stack: Vec<Arg>,
+}
+
+struct Builder<'a> {
+    function: Function,
+    arities: &'a HashMap<Label, usize>,
+}
+
+/// Number of values a function pops from the caller, following the compiler's
+/// calling convention of `mov _param_ pop`, or `pop _param_`, right after the
+/// function label.
+fn arity(program: &Program, function: &program::Function) -> usize {
+    program.0[function.start + 1..function.end]
+        .iter()
+        .take_while(|instruction| {
+            matches!(
+                instruction,
+                Instruction::Mov(Target::Id(_), Operand::Pop) | Instruction::Pop(Target::Id(_))
+            )
+        })
+        .count()
+}
+
+/// Converts one function of `program` into SSA form.
+pub fn build(program: &Program, function: &program::Function) -> Result<Function, String> {
+    let arities = program
+        .functions()
+        .iter()
+        .map(|f| (f.label.clone(), arity(program, f)))
+        .collect::<HashMap<_, _>>();
+
+    let instructions = &program.0[function.start..function.end];
+
+    // Split into blocks at labels and after jumps and returns.
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for (i, instruction) in instructions.iter().enumerate() {
+        let starts_block = i == 0
+            || matches!(instruction, Instruction::Label(_))
+            || matches!(
+                instructions[i - 1],
+                Instruction::Jmp(_)
+                    | Instruction::JmpFalse(..)
+                    | Instruction::JmpFalseOp(..)
+                    | Instruction::ScopeOut(_)
+                    | Instruction::Ret
+                    | Instruction::Halt(_)
+            );
+        match ranges.last_mut() {
+            Some(range) if !starts_block => range.1 = i + 1,
+            _ => ranges.push((i, i + 1)),
+        }
+    }
+
+    let block_of_label = ranges
+        .iter()
+        .enumerate()
+        .filter_map(|(id, (start, _))| match &instructions[*start] {
+            Instruction::Label(label) => Some((label.clone(), id)),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+    let target = |label: &Label| {
+        block_of_label
+            .get(label)
+            .copied()
+            .ok_or_else(|| format!("Jump to {} leaves the function", label))
+    };
+
+    let mut successors = vec![vec![]; ranges.len()];
+    for (id, (_, end)) in ranges.iter().enumerate() {
+        let fall_through = || {
+            (id + 1 < ranges.len())
+                .then_some(id + 1)
+                .ok_or_else(|| format!("{} runs off its end", function.label))
+        };
+        successors[id] = match &instructions[end - 1] {
+            Instruction::Jmp(label) => vec![target(label)?],
+            Instruction::JmpFalse(label, _) | Instruction::JmpFalseOp(label, ..) => {
+                vec![fall_through()?, target(label)?]
+            }
+            Instruction::ScopeOut(_) | Instruction::Ret | Instruction::Halt(_) => vec![],
+            _ => vec![fall_through()?],
+        };
+    }
+
+    // Reverse postorder over reachable blocks.
+    let mut order = vec![];
+    let mut visited = vec![false; ranges.len()];
+    let mut stack = vec![(0, 0)];
+    visited[0] = true;
+    while let Some((id, next)) = stack.pop() {
+        if let Some(&successor) = successors[id].get(next) {
+            stack.push((id, next + 1));
+            if !visited[successor] {
+                visited[successor] = true;
+                stack.push((successor, 0));
+            }
+        } else {
+            order.push(id);
+        }
+    }
+    order.reverse();
+
+    let mut preds = vec![vec![]; ranges.len()];
+    for &id in &order {
+        for &successor in &successors[id] {
+            if !preds[successor].contains(&id) {
+                preds[successor].push(id);
+            }
+        }
+    }
+    if !preds[0].is_empty() {
+        return Err(format!("{} jumps back to its entry", function.label));
+    }
+
+    let mut variables = instructions
+        .iter()
+        .filter_map(|instruction| match instruction.target() {
+            Some(Target::Id(id)) => Some(id.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    variables.sort();
+    variables.dedup();
+
+    let mut builder = Builder {
+        function: Function {
+            label: function.label.clone(),
+            params: vec![],
+            blocks: vec![],
+            next_reg: 0,
+        },
+        arities: &arities,
+    };
+
+    let params = (0..arities[&function.label])
+        .map(|_| builder.function.new_reg())
+        .collect::<Vec<_>>();
+    builder.function.params = params.clone();
+
+    let mut exits: Vec<Option<State>> = vec![None; ranges.len()];
+    let mut blocks: Vec<Option<Block>> = vec![None; ranges.len()];
+    let mut phi_slots: Vec<Vec<Slot>> = vec![vec![]; ranges.len()];
+
+    for &id in &order {
+        let processed = preds[id]
+            .iter()
+            .find_map(|pred| exits[*pred].as_ref())
+            .cloned();
+
+        let mut phis = vec![];
+        let mut state = match (id, processed) {
+            (0, _) => State {
+                vars: HashMap::new(),
+                stack: params.iter().map(|reg| Arg::Reg(*reg)).collect(),
+            },
+            (_, Some(state)) if preds[id].len() == 1 => state,
+            (_, Some(state)) => {
+                let mut entry = State::default();
+                for variable in &variables {
+                    let dst = builder.function.new_reg();
+                    phis.push(Phi {
+                        dst,
+                        incoming: vec![],
+                    });
+                    phi_slots[id].push(Slot::Var(variable.clone()));
+                    entry.vars.insert(variable.clone(), Arg::Reg(dst));
+                }
+                for i in 0..state.stack.len() {
+                    let dst = builder.function.new_reg();
+                    phis.push(Phi {
+                        dst,
+                        incoming: vec![],
+                    });
+                    phi_slots[id].push(Slot::Stack(i));
+                    entry.stack.push(Arg::Reg(dst));
+                }
+                entry
+            }
+            (_, None) => return Err(format!("Block {} has no processed predecessor", id)),
+        };
+
+        let (start, end) = ranges[id];
+        let mut insts = vec![];
+        let mut term = None;
+
+        for instruction in &instructions[start..end] {
+            term = builder.instruction(instruction, &mut state, &mut insts)?;
+        }
+
+        let term = match term {
+            Some(Terminator::Jump(_)) => Terminator::Jump(successors[id][0]),
+            Some(Terminator::Branch { cond, .. }) => Terminator::Branch {
+                cond,
+                then: successors[id][0],
+                otherwise: successors[id][1],
+            },
+            Some(term) => term,
+            None => Terminator::Jump(successors[id][0]),
+        };
+
+        let label = match &instructions[start] {
+            Instruction::Label(label) if id != 0 => Some(label.clone()),
+            _ => None,
+        };
+
+        exits[id] = Some(state);
+        blocks[id] = Some(Block {
+            label,
+            preds: preds[id].clone(),
+            phis,
+            insts,
+            term,
+        });
+    }
+
+    for &id in &order {
+        let depth = phi_slots[id]
+            .iter()
+            .filter(|slot| matches!(slot, Slot::Stack(_)))
+            .count();
+        let block = blocks[id].as_mut().unwrap();
+
+        for &pred in &preds[id] {
+            let exit = exits[pred].as_ref().unwrap();
+
+            if !block.phis.is_empty() && exit.stack.len() != depth {
+                return Err(format!("Stack depth differs at block {}", id));
+            }
+
+            for (slot, phi) in phi_slots[id].iter().zip(block.phis.iter_mut()) {
+                let arg = match slot {
+                    Slot::Var(variable) => exit.vars.get(variable).cloned(),
+                    Slot::Stack(i) => Some(exit.stack[*i].clone()),
+                };
+                phi.incoming.push((pred, arg));
+            }
+        }
+    }
+
+    // Renumber the reachable blocks in program order.
+    let mut reachable = order.clone();
+    reachable.sort();
+    let index = |id: BlockId| reachable.binary_search(&id).unwrap();
+
+    for id in &reachable {
+        let mut block = blocks[*id].take().unwrap();
+        block.preds = block.preds.into_iter().map(index).collect();
+        for phi in &mut block.phis {
+            for (pred, _) in &mut phi.incoming {
+                *pred = index(*pred);
+            }
+        }
+        block.term = match block.term {
+            Terminator::Jump(target) => Terminator::Jump(index(target)),
+            Terminator::Branch {
+                cond,
+                then,
+                otherwise,
+            } => Terminator::Branch {
+                cond,
+                then: index(then),
+                otherwise: index(otherwise),
+            },
+            term => term,
+        };
+        builder.function.blocks.push(block);
+    }
+
+    Ok(builder.function)
+}
+
+#[derive(Debug, Clone)]
+enum Slot {
+    Var(String),
+    Stack(usize),
+}
+
+impl Builder<'_> {
+    fn read(
+        &mut self,
+        operand: &Operand,
+        state: &mut State,
+        insts: &mut Vec<Inst>,
+    ) -> Result<Arg, String> {
+        match operand {
+            Operand::Id(id) => Ok(match state.vars.get(id) {
+                Some(arg) => arg.clone(),
+                None => {
+                    let dst = self.function.new_reg();
+                    insts.push(Inst::Undef {
+                        dst,
+                        name: id.clone(),
+                    });
+                    Arg::Reg(dst)
+                }
+            }),
+            Operand::Value(value) => Ok(Arg::Const(value.clone())),
+            Operand::Pop => state
+                .stack
+                .pop()
+                .ok_or_else(|| "Cannot pop. The value stack is empty".to_string()),
+        }
+    }
+
+    fn write(target: &Target, arg: Arg, state: &mut State) {
+        match target {
+            Target::Id(id) => {
+                state.vars.insert(id.clone(), arg);
+            }
+            Target::Push => state.stack.push(arg),
+        }
+    }
+
+    /// Returns a terminator with placeholder block ids for jumps and returns.
+    fn instruction(
+        &mut self,
+        instruction: &Instruction,
+        state: &mut State,
+        insts: &mut Vec<Inst>,
+    ) -> Result<Option<Terminator>, String> {
+        if let Some(op) = Op::from_instruction(instruction) {
+            let args = instruction
+                .operands()
+                .into_iter()
+                .map(|operand| self.read(operand, state, insts))
+                .collect::<Result<Vec<_>, _>>()?;
+            let dst = self.function.new_reg();
+            insts.push(Inst::Compute { dst, op, args });
+            Self::write(instruction.target().unwrap(), Arg::Reg(dst), state);
+            return Ok(None);
+        }
+
+        match instruction {
+            Instruction::Mov(target, operand) => {
+                let arg = self.read(operand, state, insts)?;
+                Self::write(target, arg, state);
+            }
+            Instruction::Push(operand) => {
+                let arg = self.read(operand, state, insts)?;
+                Self::write(&Target::Push, arg, state);
+            }
+            Instruction::Pop(target) => {
+                let arg = self.read(&Operand::Pop, state, insts)?;
+                Self::write(target, arg, state);
+            }
+            Instruction::Print(operand) => {
+                let arg = self.read(operand, state, insts)?;
+                insts.push(Inst::Print { arg });
+            }
+            Instruction::Read => {
+                let dst = self.function.new_reg();
+                insts.push(Inst::Read { dst });
+                state.stack.push(Arg::Reg(dst));
+            }
+            Instruction::Call(label) => {
+                let arity = *self
+                    .arities
+                    .get(label)
+                    .ok_or_else(|| format!("No label found for {}", label))?;
+                if state.stack.len() < arity {
+                    return Err("Cannot pop. The value stack is empty".to_string());
+                }
+                let args = state.stack.split_off(state.stack.len() - arity);
+                let dst = self.function.new_reg();
+                insts.push(Inst::Call {
+                    dst,
+                    label: label.clone(),
+                    args,
+                });
+                state.stack.push(Arg::Reg(dst));
+            }
+            Instruction::Jmp(_) => return Ok(Some(Terminator::Jump(0))),
+            Instruction::JmpFalse(_, operand) => {
+                let cond = self.read(operand, state, insts)?;
+                return Ok(Some(Terminator::Branch {
+                    cond,
+                    then: 0,
+                    otherwise: 0,
+                }));
+            }
+            Instruction::JmpFalseOp(_, op, operand1, operand2) => {
+                let args = vec![
+                    self.read(operand1, state, insts)?,
+                    self.read(operand2, state, insts)?,
+                ];
+                let dst = self.function.new_reg();
+                insts.push(Inst::Compute { dst, op: *op, args });
+                return Ok(Some(Terminator::Branch {
+                    cond: Arg::Reg(dst),
+                    then: 0,
+                    otherwise: 0,
+                }));
+            }
+            Instruction::ScopeOut(_) | Instruction::Ret => {
+                let arg = self.read(&Operand::Pop, state, insts)?;
+                if !state.stack.is_empty() {
+                    return Err("Values left on the stack at out".to_string());
+                }
+                return Ok(Some(Terminator::Return(arg)));
+            }
+            Instruction::Halt(operand) => {
+                let arg = self.read(operand, state, insts)?;
+                return Ok(Some(Terminator::Halt(arg)));
+            }
+            Instruction::Label(_) => {}
+            Instruction::ScopeIn(_)
+            | Instruction::Add(..)
+            | Instruction::Sub(..)
+            | Instruction::Mul(..)
+            | Instruction::Div(..)
+            | Instruction::Mod(..)
+            | Instruction::And(..)
+            | Instruction::Or(..)
+            | Instruction::Not(..)
+            | Instruction::Neg(..)
+            | Instruction::Eq(..)
+            | Instruction::Neq(..)
+            | Instruction::Less(..)
+            | Instruction::LessEq(..)
+            | Instruction::Greater(..)
+            | Instruction::GreaterEq(..) => {
+                return Err(format!("`{}` is not supported", instruction))
+            }
+        }
+
+        Ok(None)
+    }
+}
@@ -0,0 +1,135 @@
+use super::{Arg, BlockId, Function, Inst, Reg, Terminator};
+use crate::{instruction::Instruction, label::Label, operand::Operand, target::Target};
+
+fn register(reg: Reg) -> String {
+    format!("_%{}_", reg)
+}
+
+fn operand(arg: &Arg) -> Operand {
+    match arg {
+        Arg::Reg(reg) => Operand::Id(register(*reg)),
+        Arg::Const(value) => Operand::Value(value.clone()),
+    }
+}
+
+fn target(reg: Reg) -> Target {
+    Target::Id(register(reg))
+}
+
+fn block_label(function: &Function, id: BlockId) -> Label {
+    match &function.blocks[id].label {
+        Some(label) => label.clone(),
+        None => function.label.with_suffix(&format!("Block_{}", id)),
+    }
+}
+
+/// Phi copies for the edge `from -> to`, done through the value stack so that
+/// all sources are read before any destination is written.
+fn edge_copies(function: &Function, from: BlockId, to: BlockId, out: &mut Vec<Instruction>) {
+    let copies = function.blocks[to]
+        .phis
+        .iter()
+        .filter_map(|phi| {
+            phi.incoming
+                .iter()
+                .find(|(pred, _)| *pred == from)
+                .and_then(|(_, arg)| arg.as_ref())
+                .map(|arg| (phi.dst, arg))
+        })
+        .collect::<Vec<_>>();
+
+    for (_, arg) in &copies {
+        out.push(Instruction::Mov(Target::Push, operand(arg)));
+    }
+    for (dst, _) in copies.iter().rev() {
+        out.push(Instruction::Mov(target(*dst), Operand::Pop));
+    }
+}
+
+/// Lowers a function back to stack machine instructions, with registers
+/// stored as `_%n_` variables in the function's scope.
+pub fn lower(function: &Function) -> Vec<Instruction> {
+    let mut out = vec![Instruction::Label(function.label.clone())];
+
+    for param in function.params.iter().rev() {
+        out.push(Instruction::Mov(target(*param), Operand::Pop));
+    }
+
+    for (id, block) in function.blocks.iter().enumerate() {
+        if id != 0 {
+            out.push(Instruction::Label(block_label(function, id)));
+        }
+
+        for inst in &block.insts {
+            match inst {
+                Inst::Copy { dst, src } => out.push(Instruction::Mov(target(*dst), operand(src))),
+                Inst::Compute { dst, op, args } => {
+                    let args = args.iter().map(operand).collect();
+                    out.push(op.instruction(args, target(*dst)));
+                }
+                Inst::Undef { dst, name } => {
+                    out.push(Instruction::Mov(target(*dst), Operand::Id(name.clone())))
+                }
+                Inst::Read { dst } => {
+                    out.push(Instruction::Read);
+                    out.push(Instruction::Mov(target(*dst), Operand::Pop));
+                }
+                Inst::Print { arg } => out.push(Instruction::Print(operand(arg))),
+                Inst::Call { dst, label, args } => {
+                    for arg in args {
+                        out.push(Instruction::Mov(Target::Push, operand(arg)));
+                    }
+                    out.push(Instruction::Call(label.clone()));
+                    out.push(Instruction::Mov(target(*dst), Operand::Pop));
+                }
+            }
+        }
+
+        let next = id + 1;
+
+        match &block.term {
+            Terminator::Jump(to) => {
+                edge_copies(function, id, *to, &mut out);
+                if *to != next {
+                    out.push(Instruction::Jmp(block_label(function, *to)));
+                }
+            }
+            Terminator::Branch {
+                cond,
+                then,
+                otherwise,
+            } => {
+                let mut copies = vec![];
+                edge_copies(function, id, *otherwise, &mut copies);
+
+                if copies.is_empty() {
+                    out.push(Instruction::JmpFalse(
+                        block_label(function, *otherwise),
+                        operand(cond),
+                    ));
+                    edge_copies(function, id, *then, &mut out);
+                    if *then != next {
+                        out.push(Instruction::Jmp(block_label(function, *then)));
+                    }
+                } else {
+                    let split = function
+                        .label
+                        .with_suffix(&format!("Edge_{}_{}", id, otherwise));
+                    out.push(Instruction::JmpFalse(split.clone(), operand(cond)));
+                    edge_copies(function, id, *then, &mut out);
+                    out.push(Instruction::Jmp(block_label(function, *then)));
+                    out.push(Instruction::Label(split));
+                    out.extend(copies);
+                    out.push(Instruction::Jmp(block_label(function, *otherwise)));
+                }
+            }
+            Terminator::Return(arg) => {
+                out.push(Instruction::Mov(Target::Push, operand(arg)));
+                out.push(Instruction::Ret);
+            }
+            Terminator::Halt(arg) => out.push(Instruction::Halt(operand(arg))),
+        }
+    }
+
+    out
+}
@@ -0,0 +1,323 @@
+mod build;
+mod lower;
+mod passes;
+
+pub use build::build;
+pub use lower::lower;
+pub use passes::PassManager;
+
+use crate::{label::Label, op::Op, operand::Operand, program::Program, value::Value};
+use std::fmt::Display;
+
+pub type Reg = usize;
+pub type BlockId = usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Reg(Reg),
+    Const(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Copy {
+        dst: Reg,
+        src: Arg,
+    },
+    /// `args` are in the order `Vm::run_instruction` evaluates the operands.
+    Compute {
+        dst: Reg,
+        op: Op,
+        args: Vec<Arg>,
+    },
+    /// A variable read before any write, which fails at runtime.
+    Undef {
+        dst: Reg,
+        name: String,
+    },
+    Read {
+        dst: Reg,
+    },
+    Print {
+        arg: Arg,
+    },
+    /// `args` are in push order, so the last one is on top of the stack.
+    Call {
+        dst: Reg,
+        label: Label,
+        args: Vec<Arg>,
+    },
+}
+
+/// `None` marks a predecessor on which the variable was never assigned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phi {
+    pub dst: Reg,
+    pub incoming: Vec<(BlockId, Option<Arg>)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    /// Falls through to `then` when `cond` is truthy, like `jf` does.
+    Branch {
+        cond: Arg,
+        then: BlockId,
+        otherwise: BlockId,
+    },
+    Return(Arg),
+    /// Ends the whole run, like `halt` does.
+    Halt(Arg),
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub label: Option<Label>,
+    pub preds: Vec<BlockId>,
+    pub phis: Vec<Phi>,
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+/// One function in SSA form. Block `0` is the entry and `params` hold the
+/// values the caller pushed, the last one on top of the stack.
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub label: Label,
+    pub params: Vec<Reg>,
+    pub blocks: Vec<Block>,
+    pub next_reg: Reg,
+}
+
+impl Inst {
+    pub fn args_mut(&mut self) -> Vec<&mut Arg> {
+        match self {
+            Self::Copy { src, .. } => vec![src],
+            Self::Compute { args, .. } | Self::Call { args, .. } => args.iter_mut().collect(),
+            Self::Print { arg } => vec![arg],
+            Self::Undef { .. } | Self::Read { .. } => vec![],
+        }
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Self::Jump(block) => vec![*block],
+            Self::Branch {
+                then, otherwise, ..
+            } => vec![*then, *otherwise],
+            Self::Return(_) | Self::Halt(_) => vec![],
+        }
+    }
+}
+
+impl Function {
+    pub fn new_reg(&mut self) -> Reg {
+        self.next_reg += 1;
+        self.next_reg - 1
+    }
+
+    /// Every argument in phis, instructions and terminators.
+    pub fn args_mut(&mut self) -> Vec<&mut Arg> {
+        let mut args = vec![];
+
+        for block in &mut self.blocks {
+            for phi in &mut block.phis {
+                args.extend(phi.incoming.iter_mut().filter_map(|(_, arg)| arg.as_mut()));
+            }
+            for inst in &mut block.insts {
+                args.extend(inst.args_mut());
+            }
+            match &mut block.term {
+                Terminator::Branch { cond: arg, .. }
+                | Terminator::Return(arg)
+                | Terminator::Halt(arg) => args.push(arg),
+                Terminator::Jump(_) => {}
+            }
+        }
+
+        args
+    }
+
+    pub fn replace_uses(&mut self, reg: Reg, with: &Arg) {
+        for arg in self.args_mut() {
+            if *arg == Arg::Reg(reg) {
+                *arg = with.clone();
+            }
+        }
+    }
+}
+
+/// Converts each function to SSA, runs the default passes and lowers it back.
+/// Functions the IR cannot represent are kept as they are.
+pub fn optimize(program: Program) -> Program {
+    let mut out = vec![];
+    let functions = program.functions();
+
+    if let Some(first) = functions.first() {
+        out.extend_from_slice(&program.0[..first.start]);
+    }
+
+    for function in &functions {
+        match build(&program, function) {
+            Ok(mut ir) => {
+                PassManager::default().run(&mut ir);
+                out.extend(lower(&ir));
+            }
+            Err(e) => {
+                log::debug!("Keeping {} as is: {}", function.label, e);
+                out.extend_from_slice(&program.0[function.start..function.end]);
+            }
+        }
+    }
+
+    if functions.is_empty() {
+        return program;
+    }
+
+    Program(out)
+}
+
+impl Display for Arg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Reg(reg) => write!(f, "%{}", reg),
+            Self::Const(value) => write!(f, "{}", Operand::Value(value.clone())),
+        }
+    }
+}
+
+fn join(args: &[Arg]) -> String {
+    args.iter()
+        .map(Arg::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let params = self
+            .params
+            .iter()
+            .map(|reg| Arg::Reg(*reg))
+            .collect::<Vec<_>>();
+        writeln!(f, "function {} ({})", self.label, join(&params))?;
+
+        for (id, block) in self.blocks.iter().enumerate() {
+            let preds = block
+                .preds
+                .iter()
+                .map(|pred| format!("b{}", pred))
+                .collect::<Vec<_>>();
+            let label = block
+                .label
+                .as_ref()
+                .map_or(String::new(), |l| format!(" {}", l));
+            if preds.is_empty() {
+                writeln!(f, "b{}:{}", id, label)?;
+            } else {
+                writeln!(f, "b{}:{} ; preds: {}", id, label, preds.join(" "))?;
+            }
+
+            for phi in &block.phis {
+                let incoming = phi
+                    .incoming
+                    .iter()
+                    .map(|(pred, arg)| match arg {
+                        Some(arg) => format!("[b{} {}]", pred, arg),
+                        None => format!("[b{} undef]", pred),
+                    })
+                    .collect::<Vec<_>>();
+                writeln!(f, "  %{} = phi {}", phi.dst, incoming.join(" "))?;
+            }
+
+            for inst in &block.insts {
+                match inst {
+                    Inst::Copy { dst, src } => writeln!(f, "  %{} = {}", dst, src)?,
+                    Inst::Compute { dst, op, args } => {
+                        writeln!(f, "  %{} = {} {}", dst, op, join(args))?
+                    }
+                    Inst::Undef { dst, name } => writeln!(f, "  %{} = undef {}", dst, name)?,
+                    Inst::Read { dst } => writeln!(f, "  %{} = read", dst)?,
+                    Inst::Print { arg } => writeln!(f, "  prn {}", arg)?,
+                    Inst::Call { dst, label, args } => {
+                        writeln!(f, "  %{} = call {} ({})", dst, label, join(args))?
+                    }
+                }
+            }
+
+            match &block.term {
+                Terminator::Jump(target) => writeln!(f, "  jmp b{}", target)?,
+                Terminator::Branch {
+                    cond,
+                    then,
+                    otherwise,
+                } => writeln!(f, "  br {} b{} b{}", cond, then, otherwise)?,
+                Terminator::Return(arg) => writeln!(f, "  ret {}", arg)?,
+                Terminator::Halt(arg) => writeln!(f, "  halt {}", arg)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{golden, vm::Vm};
+
+    static LOOP: &str = r#"
+        lbl $$Function__main_$$
+        mov _result_ ""
+        mov _i_ 1
+        lbl $$Condition_Loop_1$$
+        < 28 _i_ push
+        jf $$Exit_Loop_1$$ pop
+        + _i_ _result_ _result_
+        + _i_ 1 _i_
+        jmp $$Condition_Loop_1$$
+        lbl $$Exit_Loop_1$$
+        mov push _result_
+        out
+    "#;
+
+    #[test]
+    fn test_programs_keep_results() {
+        golden::assert_results(|program, input| Vm::with_input(input).run(&optimize(program)));
+    }
+
+    #[test]
+    fn test_loop_keeps_result() {
+        let program = LOOP.parse::<Program>().unwrap();
+        let expected = Vm::default().run(&program).unwrap();
+
+        assert_eq!(Vm::default().run(&optimize(program)).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_loop_phis() {
+        let program = LOOP.parse::<Program>().unwrap();
+        let mut ir = build(&program, &program.functions()[0]).unwrap();
+        PassManager::default().run(&mut ir);
+
+        let header = ir
+            .blocks
+            .iter()
+            .find(|block| block.preds.len() == 2)
+            .unwrap();
+
+        assert_eq!(header.phis.len(), 2);
+    }
+
+    #[test]
+    fn test_reject_unbalanced_stack() {
+        // The compiler's loop condition pushes `_i_` without ever popping it.
+        let program = include_str!("../../test/fizzbuzz.4km")
+            .parse::<Program>()
+            .unwrap();
+
+        assert!(build(&program, &program.functions()[0]).is_err());
+    }
+}
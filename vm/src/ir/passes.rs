@@ -0,0 +1,319 @@
+use super::{Arg, Function, Inst, Reg, Terminator};
+use std::collections::HashMap;
+
+pub trait Pass {
+    fn name(&self) -> &'static str;
+
+    /// Returns whether the function changed.
+    fn run(&self, function: &mut Function) -> bool;
+}
+
+/// Runs its passes in order until none of them changes the function.
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    max_iterations: usize,
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self {
+            passes: vec![
+                Box::new(SimplifyPhis),
+                Box::new(ConstantFolding),
+                Box::new(CopyPropagation),
+                Box::new(SimplifyBranches),
+                Box::new(DeadCodeElimination),
+            ],
+            max_iterations: 16,
+        }
+    }
+}
+
+impl PassManager {
+    pub fn run(&self, function: &mut Function) {
+        for _ in 0..self.max_iterations {
+            let mut changed = false;
+
+            for pass in &self.passes {
+                if pass.run(function) {
+                    log::debug!("{} changed {}", pass.name(), function.label);
+                    changed = true;
+                }
+            }
+
+            if !changed {
+                break;
+            }
+        }
+    }
+}
+
+/// Number of times each register is read.
+pub fn use_counts(function: &mut Function) -> HashMap<Reg, usize> {
+    let mut counts = HashMap::new();
+
+    for arg in function.args_mut() {
+        if let Arg::Reg(reg) = arg {
+            *counts.entry(*reg).or_insert(0) += 1;
+        }
+    }
+
+    counts
+}
+
+/// Removes phis whose incoming values are all the same, ignoring the phi itself.
+pub struct SimplifyPhis;
+
+impl Pass for SimplifyPhis {
+    fn name(&self) -> &'static str {
+        "simplify-phis"
+    }
+
+    fn run(&self, function: &mut Function) -> bool {
+        let mut changed = false;
+
+        for id in 0..function.blocks.len() {
+            let mut i = 0;
+
+            while i < function.blocks[id].phis.len() {
+                let phi = &function.blocks[id].phis[i];
+                let mut incoming = phi
+                    .incoming
+                    .iter()
+                    .map(|(_, arg)| arg.as_ref())
+                    .filter(|arg| *arg != Some(&Arg::Reg(phi.dst)));
+
+                let Some(Some(first)) = incoming.next() else {
+                    i += 1;
+                    continue;
+                };
+
+                if incoming.all(|arg| arg == Some(first)) {
+                    let first = first.clone();
+                    let phi = function.blocks[id].phis.remove(i);
+                    function.replace_uses(phi.dst, &first);
+                    changed = true;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        changed
+    }
+}
+
+/// Evaluates operators on constants, keeping the ones that fail at runtime.
+pub struct ConstantFolding;
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &'static str {
+        "constant-folding"
+    }
+
+    fn run(&self, function: &mut Function) -> bool {
+        let mut changed = false;
+
+        for inst in function
+            .blocks
+            .iter_mut()
+            .flat_map(|block| &mut block.insts)
+        {
+            let Inst::Compute { dst, op, args } = inst else {
+                continue;
+            };
+            let values = args
+                .iter()
+                .map(|arg| match arg {
+                    Arg::Const(value) => Some(value.clone()),
+                    Arg::Reg(_) => None,
+                })
+                .collect::<Option<Vec<_>>>();
+
+            if let Some(Ok(value)) = values.map(|values| op.evaluate(&values)) {
+                *inst = Inst::Copy {
+                    dst: *dst,
+                    src: Arg::Const(value),
+                };
+                changed = true;
+            }
+        }
+
+        changed
+    }
+}
+
+/// Replaces reads of a copied register with the original value.
+pub struct CopyPropagation;
+
+impl Pass for CopyPropagation {
+    fn name(&self) -> &'static str {
+        "copy-propagation"
+    }
+
+    fn run(&self, function: &mut Function) -> bool {
+        let copies = function
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .filter_map(|inst| match inst {
+                Inst::Copy { dst, src } => Some((*dst, src.clone())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for (dst, src) in &copies {
+            function.replace_uses(*dst, src);
+        }
+
+        for block in &mut function.blocks {
+            block
+                .insts
+                .retain(|inst| !matches!(inst, Inst::Copy { .. }));
+        }
+
+        !copies.is_empty()
+    }
+}
+
+/// Turns branches on constants into jumps and drops the blocks that become
+/// unreachable.
+pub struct SimplifyBranches;
+
+impl Pass for SimplifyBranches {
+    fn name(&self) -> &'static str {
+        "simplify-branches"
+    }
+
+    fn run(&self, function: &mut Function) -> bool {
+        let mut changed = false;
+
+        for id in 0..function.blocks.len() {
+            let Terminator::Branch {
+                cond: Arg::Const(value),
+                then,
+                otherwise,
+            } = &function.blocks[id].term
+            else {
+                continue;
+            };
+
+            let (taken, dropped) = if value.is_truthy() {
+                (*then, *otherwise)
+            } else {
+                (*otherwise, *then)
+            };
+            function.blocks[id].term = Terminator::Jump(taken);
+
+            if taken != dropped {
+                let block = &mut function.blocks[dropped];
+                block.preds.retain(|pred| *pred != id);
+                for phi in &mut block.phis {
+                    phi.incoming.retain(|(pred, _)| *pred != id);
+                }
+            }
+            changed = true;
+        }
+
+        if changed {
+            remove_unreachable(function);
+        }
+
+        changed
+    }
+}
+
+fn remove_unreachable(function: &mut Function) {
+    let mut reachable = vec![false; function.blocks.len()];
+    let mut queue = vec![0];
+
+    while let Some(id) = queue.pop() {
+        if !reachable[id] {
+            reachable[id] = true;
+            queue.extend(function.blocks[id].term.successors());
+        }
+    }
+
+    let mut index = vec![0; function.blocks.len()];
+    let mut next = 0;
+    for (id, reachable) in reachable.iter().enumerate() {
+        index[id] = next;
+        next += *reachable as usize;
+    }
+
+    let blocks = std::mem::take(&mut function.blocks);
+    for (id, mut block) in blocks.into_iter().enumerate() {
+        if !reachable[id] {
+            continue;
+        }
+
+        block.preds.retain(|pred| reachable[*pred]);
+        block.preds.iter_mut().for_each(|pred| *pred = index[*pred]);
+        for phi in &mut block.phis {
+            phi.incoming.retain(|(pred, _)| reachable[*pred]);
+            phi.incoming
+                .iter_mut()
+                .for_each(|(pred, _)| *pred = index[*pred]);
+        }
+        block.term = match block.term {
+            Terminator::Jump(target) => Terminator::Jump(index[target]),
+            Terminator::Branch {
+                cond,
+                then,
+                otherwise,
+            } => Terminator::Branch {
+                cond,
+                then: index[then],
+                otherwise: index[otherwise],
+            },
+            term => term,
+        };
+
+        function.blocks.push(block);
+    }
+}
+
+/// Removes phis and copies whose result is never read. Operators stay, since
+/// they may fail at runtime.
+pub struct DeadCodeElimination;
+
+impl Pass for DeadCodeElimination {
+    fn name(&self) -> &'static str {
+        "dead-code-elimination"
+    }
+
+    fn run(&self, function: &mut Function) -> bool {
+        let mut changed = false;
+
+        loop {
+            let counts = use_counts(function);
+            let unused = |reg: &Reg| !counts.contains_key(reg);
+            let mut removed = false;
+
+            for block in &mut function.blocks {
+                let phis = block.phis.len();
+                block.phis.retain(|phi| {
+                    let self_uses = phi
+                        .incoming
+                        .iter()
+                        .filter(|(_, arg)| *arg == Some(Arg::Reg(phi.dst)))
+                        .count();
+                    counts.get(&phi.dst).copied().unwrap_or(0) > self_uses
+                });
+
+                let insts = block.insts.len();
+                block.insts.retain(|inst| match inst {
+                    Inst::Copy { dst, .. } => !unused(dst),
+                    _ => true,
+                });
+
+                removed |= phis != block.phis.len() || insts != block.insts.len();
+            }
+
+            if !removed {
+                return changed;
+            }
+            changed = true;
+        }
+    }
+}
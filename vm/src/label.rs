@@ -1,6 +1,6 @@
 use std::{fmt::Display, str::FromStr};
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub struct Label(String);
 
 impl FromStr for Label {
@@ -27,4 +27,29 @@ impl Label {
     pub fn new(s: &str) -> Self {
         s.parse().unwrap()
     }
+
+    pub fn function(name: &str) -> Self {
+        Self::new(&format!("$$Function_{}$$", name))
+    }
+
+    /// `$$Exit_Loop_1$$` with suffix `Inline_2` becomes `$$Exit_Loop_1_Inline_2$$`.
+    /// Labels derived from a function label mark code inside a function, so
+    /// `$$Function__f_$$` becomes `$$Body__f__Inline_2$$` rather than another
+    /// function label.
+    pub fn with_suffix(&self, suffix: &str) -> Self {
+        let base = &self.0[..self.0.len() - 2];
+        let base = match base.strip_prefix("$$Function_") {
+            Some(name) => format!("$$Body_{}", name),
+            None => base.to_string(),
+        };
+        Self(format!("{}_{}$$", base, suffix))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_function(&self) -> bool {
+        self.0.starts_with("$$Function_")
+    }
 }
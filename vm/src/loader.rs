@@ -0,0 +1,187 @@
+use crate::{
+    cfg::block_exits,
+    instruction::Instruction,
+    label::Label,
+    program::{Function, Program},
+};
+use std::collections::HashMap;
+
+/// A program checked before it runs: where each function starts and ends,
+/// and which one the run starts in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub functions: Vec<Function>,
+    /// Index into `functions`.
+    entry: usize,
+}
+
+impl Image {
+    pub fn entry(&self) -> &Function {
+        &self.functions[self.entry]
+    }
+}
+
+/// Why a program cannot be loaded, with the index of the instruction at
+/// fault, if there is one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub index: Option<usize>,
+    pub message: String,
+}
+
+impl Error {
+    fn at(index: usize, message: String) -> Self {
+        Self {
+            index: Some(index),
+            message,
+        }
+    }
+}
+
+/// Checks that every instruction belongs to a function, that labels are
+/// defined once and exist wherever they are used, and that no function can
+/// run past its last instruction, then finds `entry`.
+pub fn load(program: &Program, entry: &Label) -> Result<Image, String> {
+    check(program, entry).map_err(|e| e.message)
+}
+
+/// Like [`load`], but keeps the instruction each error is about.
+pub fn check(program: &Program, entry: &Label) -> Result<Image, Error> {
+    let functions = program.functions();
+
+    let first = functions.first().map_or(program.0.len(), |f| f.start);
+    if first > 0 {
+        return Err(Error::at(
+            0,
+            format!(
+                "Instruction 0 ({}) is outside of any function",
+                program.0[0]
+            ),
+        ));
+    }
+
+    let mut defined = HashMap::new();
+    for (i, instruction) in program.0.iter().enumerate() {
+        if let Instruction::Label(label) = instruction {
+            if let Some(first) = defined.insert(label, i) {
+                return Err(Error::at(
+                    i,
+                    format!("Label {} is defined at both {} and {}", label, first, i),
+                ));
+            }
+        }
+    }
+    for (i, instruction) in program.0.iter().enumerate() {
+        match instruction.label() {
+            Some(label) if !defined.contains_key(label) => {
+                return Err(Error::at(i, format!("No label found for {}", label)))
+            }
+            _ => {}
+        }
+    }
+
+    let block_exits = block_exits(&program.0);
+    for function in &functions {
+        let last = &program.0[function.end - 1];
+        if block_exits[function.end - 1] {
+            return Err(Error::at(
+                function.end - 1,
+                format!(
+                    "Function {} runs past its end at instruction {}; its last `out` only closes a block",
+                    function.label,
+                    function.end - 1
+                ),
+            ));
+        }
+        if !matches!(
+            last,
+            Instruction::ScopeOut(_)
+                | Instruction::Ret
+                | Instruction::Halt(_)
+                | Instruction::Jmp(_)
+        ) {
+            return Err(Error::at(
+                function.end - 1,
+                format!(
+                    "Function {} runs past its end at instruction {}; it must end with `out`, `ret`, `halt` or `jmp`",
+                    function.label,
+                    function.end - 1
+                ),
+            ));
+        }
+    }
+
+    let entry = functions
+        .iter()
+        .position(|function| function.label == *entry)
+        .ok_or_else(|| Error {
+            index: None,
+            message: format!("Entry point {} not found in the program", entry),
+        })?;
+
+    Ok(Image { functions, entry })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_main(source: &str) -> Result<Image, String> {
+        load(
+            &source.parse::<Program>().unwrap(),
+            &Label::function("_main_"),
+        )
+    }
+
+    #[test]
+    fn test_load() {
+        let program = include_str!("../test/factorial.4km")
+            .parse::<Program>()
+            .unwrap();
+        let image = load(&program, &Label::function("_factorial_")).unwrap();
+
+        assert_eq!(image.functions.len(), 2);
+        assert_eq!(image.entry().label, Label::function("_factorial_"));
+        assert_eq!(image.entry().end, program.0.len());
+    }
+
+    #[test]
+    fn test_invalid_programs() {
+        for (source, error) in [
+            (
+                "mov push 1\nlbl $$Function__main_$$\nout",
+                "Instruction 0 (mov push 1) is outside of any function",
+            ),
+            (
+                "lbl $$Function__main_$$\nlbl $$A$$\nlbl $$A$$\nout",
+                "Label $$A$$ is defined at both 1 and 2",
+            ),
+            (
+                "lbl $$Function__main_$$\njmp $$Nowhere$$",
+                "No label found for $$Nowhere$$",
+            ),
+            (
+                "lbl $$Function__main_$$\nmov push 1",
+                "Function $$Function__main_$$ runs past its end at instruction 1; \
+                 it must end with `out`, `ret`, `halt` or `jmp`",
+            ),
+            (
+                "lbl $$Function__main_$$\nin\nmov push 1\nout",
+                "Function $$Function__main_$$ runs past its end at instruction 3; \
+                 its last `out` only closes a block",
+            ),
+            (
+                "lbl $$Function__main_$$\nin\nmov push 1\nout\n\
+                 lbl $$Function__f_$$\nmov push 7\nout",
+                "Function $$Function__main_$$ runs past its end at instruction 3; \
+                 its last `out` only closes a block",
+            ),
+            (
+                "lbl $$Function__f_$$\nout",
+                "Entry point $$Function__main_$$ not found in the program",
+            ),
+        ] {
+            assert_eq!(load_main(source), Err(error.to_string()));
+        }
+    }
+}
@@ -0,0 +1,569 @@
+use crate::{
+    instruction::Instruction, label::Label, loader, program::Program, target::Target, vm::MAIN_FN,
+    wire,
+};
+use serde_json::{json, Value as Json};
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{BufRead, Write},
+};
+
+/// JSON-RPC error code of an unknown method.
+static METHOD_NOT_FOUND: i64 = -32601;
+
+/// `DiagnosticSeverity` and `CompletionItemKind` values.
+static ERROR: u64 = 1;
+static WARNING: u64 = 2;
+static VARIABLE: u64 = 6;
+static KEYWORD: u64 = 14;
+
+/// A word of an instruction, with the range of characters it covers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Word<'a> {
+    line: usize,
+    start: usize,
+    end: usize,
+    text: &'a str,
+}
+
+impl Word<'_> {
+    fn range(&self) -> Json {
+        range(self.line, self.start, self.end)
+    }
+
+    fn is_label(&self) -> bool {
+        self.text.len() >= 4 && self.text.starts_with("$$") && self.text.ends_with("$$")
+    }
+
+    /// Whether this is a `_name_`, as `Operand::from_str` reads it.
+    fn is_variable(&self) -> bool {
+        self.text.len() >= 2 && self.text.starts_with('_') && self.text.ends_with('_')
+    }
+}
+
+/// The position of byte `i` of `text` in UTF-16 code units, which is how
+/// LSP counts characters by default.
+fn column(text: &str, i: usize) -> usize {
+    text[..i].encode_utf16().count()
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    json!({
+        "start": { "line": line, "character": start },
+        "end": { "line": line, "character": end },
+    })
+}
+
+/// The range of `text` on `line`, without surrounding whitespace.
+fn line_range(line: usize, text: &str) -> Json {
+    let start = text.len() - text.trim_start().len();
+    range(
+        line,
+        column(text, start),
+        column(text, text.trim_end().len()),
+    )
+}
+
+/// Splits `text` at whitespace outside of quotes, like `Instruction::from_str`.
+fn words(line: usize, text: &str) -> Vec<Word<'_>> {
+    let mut words = vec![];
+    let mut start = None;
+    let mut quoted = false;
+
+    for (i, char) in text.char_indices().chain([(text.len(), ' ')]) {
+        if char == '"' {
+            quoted = !quoted;
+        }
+
+        match start {
+            Some(s) if char.is_whitespace() && !quoted => {
+                words.push(Word {
+                    line,
+                    start: column(text, s),
+                    end: column(text, i),
+                    text: &text[s..i],
+                });
+                start = None;
+            }
+            None if !char.is_whitespace() => start = Some(i),
+            _ => {}
+        }
+    }
+
+    words
+}
+
+/// An open `.4km` file.
+#[derive(Debug)]
+struct Document {
+    text: String,
+}
+
+impl Document {
+    /// The lines holding instructions, skipping blanks, comments and `.loc`.
+    fn instructions(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.text.lines().enumerate().filter(|(_, line)| {
+            let line = line.trim();
+            !line.is_empty() && !line.starts_with("//") && !line.starts_with(".loc")
+        })
+    }
+
+    fn words(&self) -> impl Iterator<Item = Word<'_>> {
+        self.instructions()
+            .flat_map(|(line, text)| words(line, text))
+    }
+
+    /// Label words, with whether each one defines its label.
+    fn labels(&self) -> impl Iterator<Item = (Word<'_>, bool)> {
+        self.instructions().flat_map(|(line, text)| {
+            let words = words(line, text);
+            let defines = words
+                .first()
+                .is_some_and(|word| word.text.eq_ignore_ascii_case("lbl"));
+            words
+                .into_iter()
+                .filter(Word::is_label)
+                .map(move |word| (word, defines))
+        })
+    }
+
+    fn word_at(&self, position: &Json) -> Option<Word<'_>> {
+        let line = position["line"].as_u64()? as usize;
+        let character = position["character"].as_u64()? as usize;
+
+        self.words()
+            .find(|word| word.line == line && (word.start..=word.end).contains(&character))
+    }
+
+    fn diagnostics(&self) -> Vec<Json> {
+        let mut found = vec![];
+
+        for (line, text) in self.instructions() {
+            if let Err(e) = text.parse::<Instruction>() {
+                found.push((line_range(line, text), ERROR, e));
+            }
+        }
+
+        let mut definitions = HashMap::new();
+        for (word, _) in self.labels().filter(|(_, defines)| *defines) {
+            if let Some(first) = definitions.insert(word.text, word.line) {
+                found.push((
+                    word.range(),
+                    ERROR,
+                    format!(
+                        "Label {} is already defined on line {}",
+                        word.text,
+                        first + 1
+                    ),
+                ));
+                definitions.insert(word.text, first);
+            }
+        }
+        for (word, _) in self.labels().filter(|(_, defines)| !*defines) {
+            if !definitions.contains_key(word.text) {
+                found.push((
+                    word.range(),
+                    ERROR,
+                    format!("No label found for {}", word.text),
+                ));
+            }
+        }
+
+        // The rest of what the loader checks needs a program that parses and
+        // labels that resolve.
+        if found.is_empty() {
+            let lines = self.instructions().collect::<Vec<_>>();
+            let program = Program(
+                lines
+                    .iter()
+                    .filter_map(|(_, text)| text.parse().ok())
+                    .collect(),
+            );
+            if let Err(e) = loader::check(&program, &Label::function(MAIN_FN)) {
+                found.push(match e.index.map(|i| lines[i]) {
+                    Some((line, text)) => (line_range(line, text), ERROR, e.message),
+                    // Only the entry point is missing, which a library may mean.
+                    None => (range(0, 0, 0), WARNING, e.message),
+                });
+            }
+        }
+
+        found
+            .into_iter()
+            .map(|(range, severity, message)| {
+                json!({
+                    "range": range,
+                    "severity": severity,
+                    "source": "vm",
+                    "message": message,
+                })
+            })
+            .collect()
+    }
+
+    fn hover(&self, position: &Json) -> Option<Json> {
+        let word = self.word_at(position)?;
+        let text = self.text.lines().nth(word.line)?;
+        let instruction = text.parse::<Instruction>().ok()?;
+
+        let (pops, pushes) = instruction.stack_effect();
+        let mut effect = vec![format!("pops {}, pushes {}", pops, pushes)];
+        if let Some(Target::Id(name)) = instruction.target() {
+            effect.push(format!("writes `{}`", name));
+        }
+        match instruction {
+            Instruction::Call(_) => effect.push("enters a new frame and scope".to_string()),
+            Instruction::ScopeIn(_) => {
+                effect.push("opens a block scope that sees the enclosing variables".to_string())
+            }
+            Instruction::ScopeOut(_) => effect.push(
+                "closes the innermost block, or leaves the frame and its scope; main pops its result"
+                    .to_string(),
+            ),
+            Instruction::Ret => effect.push(
+                "leaves the frame with its scope and open blocks; main pops its result".to_string(),
+            ),
+            Instruction::Halt(_) => effect.push("ends the run from any call depth".to_string()),
+            _ => {}
+        }
+
+        Some(json!({
+            "contents": {
+                "kind": "markdown",
+                "value": format!("`{}`\n\nStack effect: {}", instruction, effect.join("; ")),
+            },
+            "range": word.range(),
+        }))
+    }
+
+    /// Opcodes at the start of a line, otherwise the variables used in the
+    /// enclosing function.
+    fn completion(&self, position: &Json) -> Vec<Json> {
+        let line = position["line"].as_u64().unwrap_or(0) as usize;
+        let character = position["character"].as_u64().unwrap_or(0) as usize;
+        let before = self
+            .text
+            .lines()
+            .nth(line)
+            .map_or(String::new(), |text| text.chars().take(character).collect());
+
+        if !before.trim_start().contains(char::is_whitespace) {
+            return Instruction::mnemonics()
+                .into_iter()
+                .map(|mnemonic| json!({ "label": mnemonic, "kind": KEYWORD }))
+                .collect();
+        }
+
+        let starts = self
+            .labels()
+            .filter(|(word, defines)| *defines && word.text.starts_with("$$Function_"))
+            .map(|(word, _)| word.line)
+            .collect::<Vec<_>>();
+        let start = starts.iter().rev().find(|&&start| start <= line).copied();
+        let end = starts.iter().find(|&&end| end > line).copied();
+
+        self.words()
+            .filter(|word| Some(word.line) >= start && end.is_none_or(|end| word.line < end))
+            .filter(Word::is_variable)
+            .map(|word| word.text)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|name| json!({ "label": name, "kind": VARIABLE }))
+            .collect()
+    }
+
+    /// Definitions of the label at `position`, and also its uses unless
+    /// `definitions_only`.
+    fn label_locations(&self, uri: &Json, position: &Json, definitions_only: bool) -> Vec<Json> {
+        let Some(label) = self.word_at(position).filter(Word::is_label) else {
+            return vec![];
+        };
+
+        self.labels()
+            .filter(|(word, defines)| word.text == label.text && (*defines || !definitions_only))
+            .map(|(word, _)| json!({ "uri": uri, "range": word.range() }))
+            .collect()
+    }
+}
+
+/// Serves the language server protocol for `.4km` files until the client
+/// exits.
+pub fn serve(mut reader: impl BufRead, mut writer: impl Write) -> Result<(), String> {
+    let mut documents = HashMap::new();
+
+    while let Some(message) = wire::read(&mut reader)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        let params = &message["params"];
+        let uri = &params["textDocument"]["uri"];
+        let key = uri.as_str().unwrap_or_default().to_string();
+
+        // Notifications that change the documents.
+        let text = match method {
+            "textDocument/didOpen" => Some(params["textDocument"]["text"].as_str()),
+            "textDocument/didChange" => Some(
+                params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str()),
+            ),
+            "textDocument/didClose" => Some(None),
+            "exit" => return Ok(()),
+            _ => None,
+        };
+        if let Some(text) = text {
+            let diagnostics = match text {
+                Some(text) => {
+                    let document = Document {
+                        text: text.to_string(),
+                    };
+                    let diagnostics = document.diagnostics();
+                    documents.insert(key, document);
+                    diagnostics
+                }
+                None => {
+                    documents.remove(&key);
+                    vec![]
+                }
+            };
+
+            let notification = json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/publishDiagnostics",
+                "params": { "uri": uri, "diagnostics": diagnostics },
+            });
+            wire::write(&mut writer, &notification)?;
+            continue;
+        }
+
+        let Some(id) = message.get("id") else {
+            continue;
+        };
+        let document = documents.get(&key);
+        let position = &params["position"];
+
+        let result = match (method, document) {
+            ("initialize", _) => Some(json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": {},
+                },
+                "serverInfo": { "name": "vm" },
+            })),
+            ("shutdown", _) => Some(Json::Null),
+            ("textDocument/definition", Some(document)) => {
+                Some(json!(document.label_locations(uri, position, true)))
+            }
+            ("textDocument/references", Some(document)) => Some(json!(document.label_locations(
+                uri,
+                position,
+                !params["context"]["includeDeclaration"]
+                    .as_bool()
+                    .unwrap_or(true),
+            ))),
+            ("textDocument/hover", Some(document)) => {
+                Some(document.hover(position).unwrap_or(Json::Null))
+            }
+            ("textDocument/completion", Some(document)) => {
+                Some(json!(document.completion(position)))
+            }
+            _ => None,
+        };
+
+        let mut response = json!({ "jsonrpc": "2.0", "id": id });
+        match result {
+            Some(result) => response["result"] = result,
+            None => {
+                response["error"] = json!({
+                    "code": METHOD_NOT_FOUND,
+                    "message": format!("Unsupported method: {}", method),
+                })
+            }
+        }
+        wire::write(&mut writer, &response)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SOURCE: &str = r#"// function _main_
+lbl $$Function__main_$$
+mov _x_ 1
+jmp $$Missing$$
+lbl $$End$$
++ pop _x_ push
+lbl $$End$$
+mul _x_ 2
+jf $$End$$ _x_
+
+lbl $$Function__f_$$
+mov _y_ pop
+"#;
+
+    fn document() -> Document {
+        Document {
+            text: SOURCE.to_string(),
+        }
+    }
+
+    fn position(line: usize, character: usize) -> Json {
+        json!({ "line": line, "character": character })
+    }
+
+    #[test]
+    fn test_words() {
+        let texts = words(0, r#"  mov push "a b" "#)
+            .into_iter()
+            .map(|word| (word.start, word.end, word.text))
+            .collect::<Vec<_>>();
+
+        assert_eq!(texts, [(2, 5, "mov"), (6, 10, "push"), (11, 16, "\"a b\"")]);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let diagnostics = document().diagnostics();
+        let messages = diagnostics
+            .iter()
+            .map(|diagnostic| {
+                (
+                    diagnostic["range"]["start"]["line"].as_u64().unwrap(),
+                    diagnostic["message"].as_str().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            messages,
+            [
+                (7, "Error while parsing instruction: mul"),
+                (6, "Label $$End$$ is already defined on line 5"),
+                (3, "No label found for $$Missing$$"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_loader_diagnostics() {
+        let document = Document {
+            text: "lbl $$Function__main_$$\n// é\n  mov push \"é\"  ".to_string(),
+        };
+        let diagnostics = document.diagnostics();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["range"], range(2, 2, 14));
+        assert!(diagnostics[0]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Function $$Function__main_$$ runs past its end"));
+    }
+
+    #[test]
+    fn test_utf16_ranges() {
+        let texts = words(0, r#"prn "😀" _x_"#)
+            .into_iter()
+            .map(|word| (word.start, word.end))
+            .collect::<Vec<_>>();
+        assert_eq!(texts, [(0, 3), (4, 8), (9, 12)]);
+
+        let document = Document {
+            text: "lbl $$Function__main_$$\nprn \"😀\" _x_\nout".to_string(),
+        };
+        assert_eq!(document.diagnostics()[0]["range"], range(1, 0, 12));
+    }
+
+    #[test]
+    fn test_missing_main() {
+        let document = Document {
+            text: "lbl $$Function__f_$$\nout".to_string(),
+        };
+
+        assert_eq!(document.diagnostics()[0]["severity"], WARNING);
+    }
+
+    #[test]
+    fn test_definition_and_references() {
+        let document = document();
+        let uri = json!("file:///a.4km");
+        let lines = |locations: Vec<Json>| {
+            locations
+                .iter()
+                .map(|location| location["range"]["start"]["line"].as_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            lines(document.label_locations(&uri, &position(8, 5), true)),
+            [4, 6]
+        );
+        assert_eq!(
+            lines(document.label_locations(&uri, &position(8, 5), false)),
+            [4, 6, 8]
+        );
+        assert!(document
+            .label_locations(&uri, &position(2, 5), true)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_hover() {
+        let hover = document().hover(&position(5, 0)).unwrap();
+
+        assert_eq!(
+            hover["contents"]["value"],
+            "`+ pop _x_ push`\n\nStack effect: pops 1, pushes 1"
+        );
+        assert!(document().hover(&position(7, 0)).is_none());
+    }
+
+    #[test]
+    fn test_completion() {
+        let labels = |items: Vec<Json>| {
+            items
+                .iter()
+                .map(|item| item["label"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert!(labels(document().completion(&position(3, 1))).contains(&"jmp".to_string()));
+        assert_eq!(labels(document().completion(&position(5, 6))), ["_x_"]);
+        assert_eq!(labels(document().completion(&position(11, 4))), ["_y_"]);
+    }
+
+    #[test]
+    fn test_serve() {
+        let messages = wire::frame(&[
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+                "textDocument": { "uri": "file:///a.4km", "text": SOURCE },
+            } }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/definition", "params": {
+                "textDocument": { "uri": "file:///a.4km" },
+                "position": position(1, 6),
+            } }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "workspace/symbol", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ]);
+
+        let mut output = vec![];
+        serve(&messages[..], &mut output).unwrap();
+        let responses = wire::unframe(&output);
+
+        assert_eq!(responses.len(), 4);
+        assert_eq!(
+            responses[1]["params"]["diagnostics"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+        assert_eq!(responses[2]["result"][0]["range"]["start"]["line"], 1);
+        assert_eq!(responses[3]["error"]["code"], METHOD_NOT_FOUND);
+    }
+}
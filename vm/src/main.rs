@@ -1,34 +1,644 @@
+mod args;
+mod cfg;
+mod coverage;
+mod dap;
+mod debug;
+mod exit;
+mod fuse;
+mod golden;
+mod history;
+mod input;
 mod instruction;
+mod ir;
 mod label;
+mod loader;
+mod lsp;
+mod op;
 mod operand;
+mod opt;
+mod output;
+mod profile;
 mod program;
+mod register;
+mod repl;
+mod snapshot;
 mod target;
+mod trace;
 mod value;
 mod vm;
+mod wire;
 
-use program::Program;
-use std::{env, fs::File, io::Read};
+use args::Args;
+use cfg::Cfg;
+use exit::Failure;
+use label::Label;
+use program::{Program, SourceMap};
+use signal_hook::consts::SIGINT;
+use snapshot::Snapshot;
+use std::{
+    env,
+    fs::{self, File},
+    io::{BufWriter, Read, Write},
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use value::Value;
 use vm::Vm;
 
-fn main() -> Result<(), String> {
+static RUN_OPTIONS: &[&str] = &[
+    "--inline-threshold",
+    "--engine",
+    "--profile-folded",
+    "--coverage",
+    "--trace",
+    "--record",
+    "--replay",
+    "--snapshot",
+    "--snapshot-at",
+    "--resume",
+    "--entry",
+];
+
+static DEBUG_OPTIONS: &[&str] = &[
+    "--inline-threshold",
+    "--record",
+    "--replay",
+    "--history",
+    "--entry",
+];
+
+static USAGE: &str = "\
+Usage: vm [run] FILE [OPTIONS]
+       vm cfg|ir|disasm|debug FILE [OPTIONS]
+       vm test DIR [--bless]
+       vm dap|lsp|repl
+
+`vm run` exits with the result of main, or the operand of `halt`, when it is
+an integer from 0 to 62 other than 1, with 63 for any other integer and with
+0 for any other value. Failures exit with 64 for invalid arguments, 65 for a
+program that cannot be loaded, 70 for a runtime error, 74 for a file that
+cannot be read or written and 1 otherwise.";
+
+/// Instructions the debugger can step back by default.
+static HISTORY: usize = 100_000;
+
+fn main() -> ExitCode {
     pretty_env_logger::init();
 
-    let file_path = env::args().nth(1).expect("No file path provided");
+    match command() {
+        Ok(status) => ExitCode::from(status),
+        Err(failure) => {
+            eprintln!("Error: {:?}", failure.message());
+            ExitCode::from(failure.status())
+        }
+    }
+}
+
+/// Runs the command given on the command line and returns the exit status.
+fn command() -> Result<u8, Failure> {
+    let mut args = env::args().skip(1);
+    let Some(command) = args.next() else {
+        eprintln!("{}", USAGE);
+        return Err(Failure::Usage("No file path provided".to_string()));
+    };
+
+    let result = match command.as_str() {
+        "help" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        "run" => return run(Args::parse(args, RUN_OPTIONS).map_err(Failure::Usage)?),
+        "cfg" => cfg(Args::parse(args, &[]).map_err(Failure::Usage)?),
+        "ir" => ir(Args::parse(args, &[]).map_err(Failure::Usage)?),
+        "disasm" => disasm(Args::parse(args, RUN_OPTIONS).map_err(Failure::Usage)?),
+        "debug" => debug(Args::parse(args, DEBUG_OPTIONS).map_err(Failure::Usage)?),
+        "dap" => dap::serve(std::io::stdin().lock(), std::io::stdout()).map_err(Failure::Other),
+        "lsp" => lsp::serve(std::io::stdin().lock(), std::io::stdout()).map_err(Failure::Other),
+        "repl" => repl().map_err(Failure::Other),
+        "test" => {
+            test(Args::parse(args, &["--max-instructions", "--timeout"]).map_err(Failure::Usage)?)
+                .map_err(Failure::Other)
+        }
+        _ => {
+            return run(
+                Args::parse(std::iter::once(command).chain(args), RUN_OPTIONS)
+                    .map_err(Failure::Usage)?,
+            )
+        }
+    };
+
+    result.map(|()| 0)
+}
+
+fn path(args: &Args) -> Result<&str, Failure> {
+    args.positional(0)
+        .ok_or_else(|| Failure::Usage("No file path provided".to_string()))
+}
+
+fn read_source(args: &Args) -> Result<String, Failure> {
+    let file_path = path(args)?;
+    let read_error = |e| Failure::Io(format!("Failed to read {}: {}", file_path, e));
+
+    let mut file = File::open(file_path).map_err(read_error)?;
+
+    let mut source = String::new();
+
+    file.read_to_string(&mut source).map_err(read_error)?;
+
+    Ok(source)
+}
+
+fn read_program(args: &Args) -> Result<Program, Failure> {
+    read_source(args)?
+        .parse::<Program>()
+        .map_err(Failure::Program)
+}
+
+struct Loaded {
+    program: Program,
+    /// Where each instruction came from, unless `-O` rewrote them.
+    map: Option<SourceMap>,
+    /// Fingerprint of the program as written.
+    fingerprint: u64,
+}
+
+/// Reads the program and applies `-O` and, if `fusion` is set, instruction
+/// fusion as requested.
+fn load_program(args: &Args, fusion: bool) -> Result<Loaded, Failure> {
+    let (mut program, map) = Program::parse_with_source_map(&read_source(args)?, path(args)?)
+        .map_err(Failure::Program)?;
+    let mut map = Some(map);
+    let fingerprint = program.fingerprint();
+
+    if args.flag("-O") {
+        let mut options = opt::Options {
+            ssa: args.flag("--ssa"),
+            entry: entry(args).map_err(Failure::Usage)?,
+            ..Default::default()
+        };
+
+        if let Some(threshold) = args.option("--inline-threshold") {
+            options.inline_threshold = threshold
+                .parse()
+                .map_err(|_| Failure::Usage(format!("Invalid inline threshold: {}", threshold)))?;
+        }
+
+        let stats;
+        (program, stats) = opt::optimize(program, &options);
+        map = None;
+
+        if args.flag("--stats") {
+            eprintln!("{}", stats);
+        }
+    }
+
+    // Coverage is reported per source line and snapshots name instructions
+    // by index, so keep instructions as written.
+    let as_written = ["--coverage", "--snapshot", "--snapshot-at", "--resume"]
+        .iter()
+        .any(|option| args.option(option).is_some());
+    if fusion && !args.flag("--no-fuse") && !as_written {
+        let origin;
+        (program, origin) = fuse::fuse(program);
+        map = map.map(|map| map.select(&origin));
+    }
+
+    Ok(Loaded {
+        program,
+        map,
+        fingerprint,
+    })
+}
+
+/// The input for `read`, recorded or replayed as requested.
+fn input(args: &Args, fingerprint: u64) -> Result<Box<dyn input::Input>, String> {
+    match (args.option("--record"), args.option("--replay")) {
+        (Some(_), Some(_)) => Err("Cannot both record and replay input".to_string()),
+        (Some(path), None) => {
+            let file =
+                File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+            Ok(Box::new(input::Recorder::new(
+                input::Stdin,
+                file,
+                fingerprint,
+            )?))
+        }
+        (None, Some(path)) => {
+            let recording =
+                fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+            Ok(Box::new(input::Replay::parse(&recording, fingerprint)?))
+        }
+        (None, None) => Ok(Box::default()),
+    }
+}
+
+/// Returns the exit status for the value the run ended with.
+fn run(args: Args) -> Result<u8, Failure> {
+    let folded = args.option("--profile-folded");
+    let coverage_path = args.option("--coverage");
+
+    if coverage_path.is_some() && args.flag("-O") {
+        return Err(Failure::Usage(
+            "Coverage cannot be combined with -O".to_string(),
+        ));
+    }
+
+    let Loaded {
+        program,
+        map,
+        fingerprint,
+    } = load_program(&args, true)?;
+    let input = input(&args, fingerprint).map_err(Failure::Io)?;
+
+    let mut profiler =
+        (args.flag("--profile") || folded.is_some()).then(|| profile::Profiler::new(&program));
+    let mut coverage = coverage_path.map(|_| coverage::Coverage::new(&program));
+
+    let mut hooks: Vec<&mut dyn vm::Hook> = vec![];
+    if let Some(profiler) = &mut profiler {
+        hooks.push(profiler);
+    }
+    if let Some(coverage) = &mut coverage {
+        hooks.push(coverage);
+    }
+    let mut tracer = match args.option("--trace") {
+        Some(path) => Some(trace::Tracer::new(BufWriter::new(
+            File::create(path)
+                .map_err(|e| Failure::Io(format!("Failed to create {}: {}", path, e)))?,
+        ))),
+        None => None,
+    };
+    if let Some(tracer) = &mut tracer {
+        hooks.push(tracer);
+    }
+
+    let snapshot_path = args.option("--snapshot");
+    let snapshot_at = match args.option("--snapshot-at") {
+        Some(at) => Some(
+            at.parse::<usize>()
+                .map_err(|_| Failure::Usage(format!("Invalid instruction index: {}", at)))?,
+        ),
+        None => None,
+    };
+    if snapshot_at.is_some() && snapshot_path.is_none() {
+        return Err(Failure::Usage(
+            "--snapshot-at requires --snapshot".to_string(),
+        ));
+    }
+    let snapshots = snapshot_path.is_some() || args.option("--resume").is_some();
+
+    let entry = entry(&args).map_err(Failure::Usage)?;
+    let program_args = args.rest().iter().map(|arg| program_arg(arg)).collect();
+    if args.option("--resume").is_some()
+        && (args.option("--entry").is_some() || !args.rest().is_empty())
+    {
+        return Err(Failure::Usage(
+            "--entry and program arguments cannot be combined with --resume".to_string(),
+        ));
+    }
+
+    // `None` when the run paused for a snapshot.
+    let mut vm = None;
+    let result = match args.option("--engine").unwrap_or("stack") {
+        "stack" => {
+            let vm = vm.insert(match args.option("--resume") {
+                Some(path) => {
+                    let snapshot = fs::read_to_string(path)
+                        .map_err(|e| Failure::Io(format!("Failed to read {}: {}", path, e)))?;
+                    Snapshot::parse(&snapshot)
+                        .and_then(|snapshot| Vm::resume(snapshot, &program, input))
+                        .map_err(Failure::Program)?
+                }
+                None => {
+                    let mut vm = Vm::with_input(input);
+                    vm.enter(&program, &entry, program_args)
+                        .map_err(Failure::Program)?;
+                    vm
+                }
+            });
+            match snapshot_path {
+                Some(path) => run_until_snapshot(vm, &program, &mut hooks, path, snapshot_at),
+                None if hooks.is_empty() => vm.run(&program).map(Some),
+                None => vm.run_with_hook(&program, &mut hooks).map(Some),
+            }
+        }
+        "register" if hooks.is_empty() && !snapshots => {
+            let program =
+                register::RegisterProgram::translate(&program, &entry).map_err(Failure::Program)?;
+            register::RegisterVm::with_input(input)
+                .run(&program, program_args)
+                .map(Some)
+        }
+        "register" => {
+            return Err(Failure::Usage(
+                "Profiling, coverage, tracing and snapshots require the stack engine".to_string(),
+            ))
+        }
+        engine => return Err(Failure::Usage(format!("Unknown engine: {}", engine))),
+    };
+
+    if let Some(tracer) = tracer {
+        tracer
+            .finish(result.as_ref().err().map(String::as_str))
+            .map_err(Failure::Io)?;
+    }
 
-    let mut file = File::open(file_path).expect("File not found");
+    let result = result.map_err(|e| {
+        let Some(vm) = &vm else {
+            return e;
+        };
 
-    let mut program = String::new();
+        eprint!("{}", vm.backtrace().render(map.as_ref()));
+        match map.as_ref().and_then(|map| map.position(vm.ip())) {
+            Some(position) => format!("{}: {}", position, e),
+            None => e,
+        }
+    });
 
-    file.read_to_string(&mut program)
-        .expect("Error while reading file");
+    if let Some(profiler) = &mut profiler {
+        profiler.finish();
+        eprint!("{}", profiler.report(&program, map.as_ref()));
+    }
+    if let (Some(path), Some(profiler)) = (folded, &profiler) {
+        fs::write(path, profiler.folded())
+            .map_err(|e| Failure::Io(format!("Failed to write {}: {}", path, e)))?;
+    }
 
-    let program = program.parse::<Program>()?;
+    if let (Some(path), Some(coverage)) = (coverage_path, &coverage) {
+        // Runs accumulate into an existing tracefile.
+        let mut report = match fs::read_to_string(path) {
+            Ok(existing) => coverage::Report::parse(&existing).map_err(Failure::Io)?,
+            Err(_) => coverage::Report::default(),
+        };
+        let map = map.as_ref().unwrap();
+        report.add(&map.path, &coverage.record(&program, &map.lines));
 
-    let mut vm = Vm::default();
+        fs::write(path, report.to_string())
+            .map_err(|e| Failure::Io(format!("Failed to write {}: {}", path, e)))?;
+    }
+
+    match result.map_err(Failure::Runtime)? {
+        Some(result) => {
+            println!("Result: {:?}", result);
+            Ok(exit::status(&result))
+        }
+        None => Ok(0),
+    }
+}
+
+/// The function given with `--entry`, by name or label, or main.
+fn entry(args: &Args) -> Result<Label, String> {
+    match args.option("--entry") {
+        Some(label) if label.starts_with("$$") => label.parse(),
+        Some(name) => Ok(Label::function(name)),
+        None => Ok(Label::function(vm::MAIN_FN)),
+    }
+}
+
+/// A program argument as a value literal, or as a string if it is not one,
+/// so that bare words need no quotes.
+fn program_arg(arg: &str) -> Value {
+    Value::from_str(arg).unwrap_or_else(|_| Value::String(arg.into()))
+}
+
+/// Runs until main returns, or until the run reaches instruction `at` or is
+/// interrupted with Ctrl-C, in which case its state is written to `path`.
+fn run_until_snapshot(
+    vm: &mut Vm,
+    program: &Program,
+    hook: &mut dyn vm::Hook,
+    path: &str,
+    at: Option<usize>,
+) -> Result<Option<Value>, String> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGINT, interrupted.clone())
+        .map_err(|e| format!("Failed to handle Ctrl-C: {}", e))?;
+
+    // A resumed run does not pause again before its first instruction.
+    let mut first = true;
+    loop {
+        if vm.is_started()
+            && !first
+            && (Some(vm.ip()) == at || interrupted.swap(false, Ordering::Relaxed))
+        {
+            fs::write(path, vm.snapshot(program).to_json())
+                .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+            eprintln!("Snapshot written to {} at instruction {}", path, vm.ip());
+            return Ok(None);
+        }
+        first = false;
+
+        if let Some(value) = vm.step(program, hook)? {
+            return Ok(Some(value));
+        }
+    }
+}
+
+/// Reads debugger commands from stdin, which `read` instructions share
+/// unless input is replayed.
+fn debug(args: Args) -> Result<(), Failure> {
+    use debug::Stop;
+
+    // Breakpoints and the indices shown are those of the program as written.
+    let Loaded {
+        program,
+        map,
+        fingerprint,
+    } = load_program(&args, false)?;
+    let capacity = match args.option("--history") {
+        Some(n) => n
+            .parse()
+            .map_err(|_| Failure::Usage(format!("Invalid history size: {}", n)))?,
+        None => HISTORY,
+    };
+    let vm = Vm::with_input(input(&args, fingerprint).map_err(Failure::Io)?);
+    let entry = entry(&args).map_err(Failure::Usage)?;
+    let program_args = args.rest().iter().map(|arg| program_arg(arg)).collect();
+    let mut debugger = debug::Debugger::new(&program, vm, &entry, program_args, capacity)
+        .map_err(Failure::Program)?;
+
+    let show = |vm: &Vm| {
+        let position = map
+            .as_ref()
+            .and_then(|map| map.position(vm.ip()))
+            .map_or(String::new(), |position| format!("  ({})", position));
+        match program.0.get(vm.ip()) {
+            Some(instruction) => eprintln!("{:>4}: {}{}", vm.ip(), instruction, position),
+            None => eprintln!("{:>4}: end of program", vm.ip()),
+        }
+    };
+    show(debugger.vm());
+
+    for line in std::io::stdin().lines() {
+        let line = line.map_err(|e| Failure::Io(format!("Failed to read command: {}", e)))?;
+        let mut words = line.split_whitespace();
+
+        let stop = match (words.next(), words.next()) {
+            (None, _) => continue,
+            (Some("s" | "step"), _) => debugger.step(),
+            (Some("b" | "back"), _) => debugger.back(),
+            (Some("c" | "continue"), _) => debugger.resume(),
+            (Some("rc" | "reverse-continue"), _) => debugger.reverse(),
+            (Some("break"), Some(i)) => {
+                let Ok(i) = i.parse() else {
+                    eprintln!("Invalid instruction index: {}", i);
+                    continue;
+                };
+                if !debugger.breakpoints.remove(&i) {
+                    debugger.breakpoints.insert(i);
+                }
+                eprintln!("Breakpoints: {:?}", debugger.breakpoints);
+                continue;
+            }
+            (Some("last-write"), Some(name)) => {
+                if debugger.last_write(name).is_none() {
+                    eprintln!("No write to {} in this frame's history", name);
+                }
+                Stop::Step
+            }
+            (Some("p" | "print"), _) => {
+                eprint!("{}", debugger.vm().backtrace().render(map.as_ref()));
+                eprintln!("Stack: {:?}", debugger.vm().stack());
+                continue;
+            }
+            (Some("q" | "quit"), _) => break,
+            (Some(command), _) => {
+                eprintln!(
+                    "Unknown command: {}. Commands: step, back, continue, reverse-continue, \
+                     break INDEX, last-write NAME, print, quit",
+                    command
+                );
+                continue;
+            }
+        };
+
+        match stop {
+            Stop::Start => eprintln!("Reached the start of the history"),
+            Stop::Finished(result) => eprintln!("Result: {:?}", result),
+            Stop::Error(e) => eprintln!("Error: {}", e),
+            Stop::Step | Stop::Breakpoint => {}
+        }
+        show(debugger.vm());
+    }
+
+    Ok(())
+}
+
+/// Reads instructions from stdin until it ends; `read` takes the next line.
+fn repl() -> Result<(), String> {
+    let mut repl = repl::Repl::new(Vm::default());
+    let prompt = |repl: &repl::Repl| {
+        print!("{}", repl.prompt());
+        std::io::stdout().flush().ok();
+    };
+
+    prompt(&repl);
+    for line in std::io::stdin().lines() {
+        let line = line.map_err(|e| format!("Failed to read line: {}", e))?;
+        match repl.line(&line) {
+            Ok(out) if out.is_empty() => {}
+            Ok(out) => println!("{}", out),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+        prompt(&repl);
+    }
+    println!();
+
+    Ok(())
+}
+
+/// Runs the programs in a directory against their fixtures, or with
+/// `--bless` rewrites the fixtures to match.
+fn test(args: Args) -> Result<(), String> {
+    let dir = args.positional(0).ok_or("No test directory provided")?;
+    let mut limits = golden::Limits::default();
+    if let Some(n) = args.option("--max-instructions") {
+        limits.instructions = n
+            .parse()
+            .map_err(|_| format!("Invalid instruction limit: {}", n))?;
+    }
+    if let Some(ms) = args.option("--timeout") {
+        limits.time = std::time::Duration::from_millis(
+            ms.parse()
+                .map_err(|_| format!("Invalid timeout in milliseconds: {}", ms))?,
+        );
+    }
+
+    let cases = golden::Case::discover(std::path::Path::new(dir))?;
+    if args.flag("--bless") {
+        for case in &cases {
+            case.bless(&case.run(limits)?)?;
+            println!("blessed {}", case.name());
+        }
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for case in &cases {
+        let outcome = case.run(limits)?;
+        let mismatches = case.check(&outcome)?;
+        if mismatches.is_empty() {
+            println!("ok      {}", case.name());
+        } else {
+            failed += 1;
+            println!("FAILED  {}", case.name());
+            for mismatch in mismatches {
+                println!("{}", mismatch);
+            }
+        }
+    }
+
+    println!("\n{} passed, {} failed", cases.len() - failed, failed);
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{} of {} tests failed", failed, cases.len())),
+    }
+}
+
+fn cfg(args: Args) -> Result<(), Failure> {
+    let program = read_program(&args)?;
+    let cfg = Cfg::build(&program).map_err(Failure::Program)?;
+
+    if args.flag("--dot") {
+        print!("{}", cfg.to_dot(&program));
+    } else {
+        print!("{}", cfg.to_text(&program));
+    }
+
+    Ok(())
+}
+
+fn ir(args: Args) -> Result<(), Failure> {
+    let program = read_program(&args)?;
+
+    for function in program.functions() {
+        let mut ir = match ir::build(&program, &function) {
+            Ok(ir) => ir,
+            Err(e) => {
+                eprintln!("Skipping {}: {}", function.label, e);
+                continue;
+            }
+        };
+
+        if !args.flag("--raw") {
+            ir::PassManager::default().run(&mut ir);
+        }
+
+        println!("{}", ir);
+    }
+
+    Ok(())
+}
 
-    let result = vm.run(&program)?;
+fn disasm(args: Args) -> Result<(), Failure> {
+    let Loaded { program, .. } = load_program(&args, true)?;
 
-    println!("Result: {:?}", result);
+    for (i, instruction) in program.0.iter().enumerate() {
+        println!("{:>4}: {}", i, instruction);
+    }
 
     Ok(())
 }
@@ -0,0 +1,149 @@
+use crate::{instruction::Instruction, operand::Operand, target::Target, value::Value};
+use std::fmt::Display;
+
+/// The operators shared by `Instruction`, the SSA IR and the register engine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    And,
+    Or,
+    Not,
+    Neg,
+    Eq,
+    Neq,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+}
+
+impl Op {
+    pub fn from_instruction(instruction: &Instruction) -> Option<Self> {
+        use Instruction::*;
+
+        Some(match instruction {
+            Add(..) => Self::Add,
+            Sub(..) => Self::Sub,
+            Mul(..) => Self::Mul,
+            Div(..) => Self::Div,
+            Mod(..) => Self::Mod,
+            And(..) => Self::And,
+            Or(..) => Self::Or,
+            Not(..) => Self::Not,
+            Neg(..) => Self::Neg,
+            Eq(..) => Self::Eq,
+            Neq(..) => Self::Neq,
+            Less(..) => Self::Less,
+            LessEq(..) => Self::LessEq,
+            Greater(..) => Self::Greater,
+            GreaterEq(..) => Self::GreaterEq,
+            _ => return None,
+        })
+    }
+
+    pub fn instruction(self, mut args: Vec<Operand>, target: Target) -> Instruction {
+        use Instruction::*;
+
+        if let Self::Not | Self::Neg = self {
+            let operand = args.remove(0);
+            return match self {
+                Self::Not => Not(operand, target),
+                _ => Neg(operand, target),
+            };
+        }
+
+        let operand2 = args.remove(1);
+        let operand1 = args.remove(0);
+
+        match self {
+            Self::Add => Add(operand1, operand2, target),
+            Self::Sub => Sub(operand1, operand2, target),
+            Self::Mul => Mul(operand1, operand2, target),
+            Self::Div => Div(operand1, operand2, target),
+            Self::Mod => Mod(operand1, operand2, target),
+            Self::And => And(operand1, operand2, target),
+            Self::Or => Or(operand1, operand2, target),
+            Self::Eq => Eq(operand1, operand2, target),
+            Self::Neq => Neq(operand1, operand2, target),
+            Self::Less => Less(operand1, operand2, target),
+            Self::LessEq => LessEq(operand1, operand2, target),
+            Self::Greater => Greater(operand1, operand2, target),
+            _ => GreaterEq(operand1, operand2, target),
+        }
+    }
+
+    /// Computes the result from the evaluated operands, with the operand
+    /// order quirks of `Vm::run_instruction`.
+    pub fn evaluate(self, values: &[Value]) -> Result<Value, String> {
+        match (self, values) {
+            (Self::Add, [value1, value2]) => value2.add(value1),
+            (Self::Sub, [value1, value2]) => value2.sub(value1),
+            (Self::Mul, [value1, value2]) => value1.mul(value2),
+            (Self::Div, [value1, value2]) => value2.div(value1),
+            (Self::Mod, [value1, value2]) => value2.modulo(value1),
+            (Self::Eq, [value1, value2]) => value1.eq(value2),
+            (Self::Neq, [value1, value2]) => value1.ne(value2),
+            (Self::Less, [value1, value2]) => value2.lt(value1),
+            (Self::LessEq, [value1, value2]) => value2.le(value1),
+            (Self::Greater, [value1, value2]) => value2.gt(value1),
+            (Self::GreaterEq, [value1, value2]) => value2.ge(value1),
+            (Self::And, [value1, value2]) => value1.and(value2),
+            (Self::Or, [value1, value2]) => value1.or(value2),
+            (Self::Not, [value]) => value.not(),
+            (Self::Neg, [value]) => value.neg(),
+            _ => Err(format!("Wrong number of operands for {}", self)),
+        }
+    }
+
+    pub const ALL: [Self; 15] = [
+        Self::Add,
+        Self::Sub,
+        Self::Mul,
+        Self::Div,
+        Self::Mod,
+        Self::And,
+        Self::Or,
+        Self::Not,
+        Self::Neg,
+        Self::Eq,
+        Self::Neq,
+        Self::Less,
+        Self::LessEq,
+        Self::Greater,
+        Self::GreaterEq,
+    ];
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Mod => "%",
+            Self::And => "&",
+            Self::Or => "|",
+            Self::Not => "!",
+            Self::Neg => "neg",
+            Self::Eq => "==",
+            Self::Neq => "!=",
+            Self::Less => "<",
+            Self::LessEq => "<=",
+            Self::Greater => ">",
+            Self::GreaterEq => ">=",
+        }
+    }
+
+    pub fn from_mnemonic(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|op| op.mnemonic() == s)
+    }
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.mnemonic())
+    }
+}
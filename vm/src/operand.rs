@@ -1,5 +1,5 @@
 use crate::value::Value;
-use std::{collections::HashMap, str::FromStr};
+use std::{collections::HashMap, fmt::Display, str::FromStr};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Operand {
@@ -45,3 +45,14 @@ impl Operand {
         }
     }
 }
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Id(id) => write!(f, "{}", id),
+            Self::Value(Value::String(s)) => write!(f, "\"{}\"", s),
+            Self::Value(value) => write!(f, "{}", value),
+            Self::Pop => write!(f, "pop"),
+        }
+    }
+}
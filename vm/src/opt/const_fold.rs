@@ -0,0 +1,121 @@
+use crate::{
+    instruction::Instruction, operand::Operand, program::Program, target::Target, value::Value,
+};
+use std::collections::HashMap;
+
+/// Evaluates operators whose operands are literals and propagates constants
+/// stored in variables until the end of the basic block.
+///
+/// Folding goes through `Instruction::evaluate`, so an operation that would
+/// fail at runtime is left untouched and still fails with the same message.
+pub fn run(program: Program) -> Program {
+    let mut constants: HashMap<String, Value> = HashMap::new();
+    let mut out = Vec::with_capacity(program.0.len());
+
+    for mut instruction in program.0 {
+        if matches!(instruction, Instruction::Label(_)) {
+            constants.clear();
+        }
+
+        for operand in instruction.operands_mut() {
+            if let Operand::Id(id) = operand {
+                if let Some(value) = constants.get(id) {
+                    *operand = Operand::Value(value.clone());
+                }
+            }
+        }
+
+        let instruction = match fold(&instruction) {
+            Some(folded) => folded,
+            None => instruction,
+        };
+
+        match &instruction {
+            Instruction::Mov(Target::Id(id), Operand::Value(value)) => {
+                constants.insert(id.clone(), value.clone());
+            }
+            Instruction::JmpFalse(_, Operand::Value(value)) if value.is_truthy() => continue,
+            instruction => {
+                if let Some(Target::Id(id)) = instruction.target() {
+                    constants.remove(id);
+                }
+            }
+        }
+
+        out.push(instruction);
+    }
+
+    Program(out)
+}
+
+fn fold(instruction: &Instruction) -> Option<Instruction> {
+    match instruction {
+        Instruction::JmpFalse(label, Operand::Value(value)) if !value.is_truthy() => {
+            Some(Instruction::Jmp(label.clone()))
+        }
+        Instruction::Mov(..) | Instruction::JmpFalse(..) | Instruction::Print(_) => None,
+        _ => {
+            let target = instruction.target()?;
+            let values = instruction
+                .operands()
+                .into_iter()
+                .map(|operand| match operand {
+                    Operand::Value(value) => Some(value.clone()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+            let value = instruction.evaluate(&values).ok()?;
+
+            Some(Instruction::Mov(target.clone(), Operand::Value(value)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimize(source: &str) -> Vec<String> {
+        run(source.parse::<Program>().unwrap())
+            .0
+            .iter()
+            .map(Instruction::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_fold_literals() {
+        assert_eq!(optimize("+ 1 27 push"), vec!["mov push 28"]);
+        assert_eq!(optimize("- 1 27 _x_"), vec!["mov _x_ 26"]);
+        assert_eq!(optimize(r#"+ 1 "a" push"#), vec![r#"mov push "a1""#]);
+    }
+
+    #[test]
+    fn test_keep_runtime_errors() {
+        assert_eq!(optimize(r#"- 1 "a" push"#), vec![r#"- 1 "a" push"#]);
+    }
+
+    #[test]
+    fn test_propagate_within_block() {
+        assert_eq!(
+            optimize("mov _x_ 2\n* _x_ 3 _y_\nprn _y_\nlbl $$L$$\nprn _y_"),
+            vec!["mov _x_ 2", "mov _y_ 6", "prn 6", "lbl $$L$$", "prn _y_"]
+        );
+    }
+
+    #[test]
+    fn test_reassignment_stops_propagation() {
+        assert_eq!(
+            optimize("mov _x_ 2\nmov _x_ pop\nprn _x_"),
+            vec!["mov _x_ 2", "mov _x_ pop", "prn _x_"]
+        );
+    }
+
+    #[test]
+    fn test_fold_branches() {
+        assert_eq!(
+            optimize("jf $$A$$ 0\njf $$B$$ 1\nprn 1"),
+            vec!["jmp $$A$$", "prn 1"]
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use crate::{cfg::Cfg, instruction::Instruction, label::Label, program::Program};
+use std::collections::HashSet;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Removed {
+    pub instructions: usize,
+    pub labels: usize,
+    pub functions: Vec<Label>,
+}
+
+/// Removes code that is unreachable from the `entry` function through jumps
+/// and calls, then labels that nothing targets.
+pub fn run(program: Program, entry: &Label) -> (Program, Removed) {
+    let mut removed = Removed::default();
+
+    let Some(&start) = program.labels().get(entry) else {
+        return (program, removed);
+    };
+    let Ok(cfg) = Cfg::build(&program) else {
+        return (program, removed);
+    };
+
+    let mut reachable = vec![false; cfg.blocks.len()];
+    let mut queue = vec![cfg
+        .blocks
+        .iter()
+        .position(|block| block.start == start)
+        .unwrap()];
+
+    while let Some(id) = queue.pop() {
+        if !reachable[id] {
+            reachable[id] = true;
+            queue.extend(cfg.successors(id));
+        }
+    }
+
+    let mut live = vec![false; program.0.len()];
+    for block in cfg.blocks.iter().filter(|block| reachable[block.id]) {
+        live[block.start..block.end].fill(true);
+    }
+
+    let targeted = program
+        .0
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| live[*i])
+        .filter_map(|(_, instruction)| match instruction {
+            Instruction::Jmp(label)
+            | Instruction::JmpFalse(label, _)
+            | Instruction::JmpFalseOp(label, ..)
+            | Instruction::Call(label) => Some(label.clone()),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    let mut out = Vec::with_capacity(program.0.len());
+
+    for (i, instruction) in program.0.into_iter().enumerate() {
+        if !live[i] {
+            if let Instruction::Label(label) = &instruction {
+                if label.is_function() {
+                    removed.functions.push(label.clone());
+                }
+            }
+            removed.instructions += 1;
+            continue;
+        }
+
+        if let Instruction::Label(label) = &instruction {
+            if label != entry && !targeted.contains(label) {
+                removed.labels += 1;
+                continue;
+            }
+        }
+
+        out.push(instruction);
+    }
+
+    (Program(out), removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::MAIN_FN;
+
+    #[test]
+    fn test_remove_dead_code() {
+        let program = r#"
+            lbl $$Function__unused_$$
+            out
+            lbl $$Function__main_$$
+            mov push 1
+            jf $$Else_Conditional_1$$ pop
+            mov push "then"
+            out
+            jmp $$Exit_Conditional_1$$
+            lbl $$Else_Conditional_1$$
+            lbl $$Exit_Conditional_1$$
+            mov push "else"
+            out
+            "#
+        .parse::<Program>()
+        .unwrap();
+
+        let (program, removed) = run(program, &Label::function(MAIN_FN));
+
+        assert_eq!(
+            program
+                .0
+                .iter()
+                .map(Instruction::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "lbl $$Function__main_$$",
+                "mov push 1",
+                "jf $$Else_Conditional_1$$ pop",
+                r#"mov push "then""#,
+                "out",
+                "lbl $$Else_Conditional_1$$",
+                r#"mov push "else""#,
+                "out",
+            ]
+        );
+        assert_eq!(
+            removed,
+            Removed {
+                instructions: 3,
+                labels: 1,
+                functions: vec![Label::new("$$Function__unused_$$")],
+            }
+        );
+    }
+
+    #[test]
+    fn test_keep_program_without_main() {
+        let program = "lbl $$Function__f_$$\nout".parse::<Program>().unwrap();
+        let (program, removed) = run(program, &Label::function(MAIN_FN));
+
+        assert_eq!(program.0.len(), 2);
+        assert_eq!(removed, Removed::default());
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    instruction::Instruction, label::Label, operand::Operand, program::Program, target::Target,
+};
+use std::collections::{HashMap, HashSet};
+
+/// Replaces calls to small non-recursive functions with a copy of their body.
+///
+/// Arguments and the return value keep travelling through the value stack, so
+/// the spliced body pops its parameters as before and each `out` becomes a jump
+/// to a continuation label. Variables and labels get a per-call-site suffix,
+/// since the body now runs in the caller's scope.
+pub fn run(program: Program, threshold: usize) -> Program {
+    let bodies = program
+        .functions()
+        .into_iter()
+        .map(|function| {
+            let body = program.0[function.start + 1..function.end].to_vec();
+            (function.label, body)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let inlinable = bodies
+        .iter()
+        .filter(|(label, body)| {
+            size(body) <= threshold
+                && ends_with_exit(body)
+                && !opens_blocks(body)
+                && !is_recursive(label, &bodies)
+        })
+        .map(|(label, _)| label.clone())
+        .collect::<HashSet<_>>();
+
+    let mut out = Vec::with_capacity(program.0.len());
+    let mut site = 0;
+
+    for instruction in program.0 {
+        match instruction {
+            Instruction::Call(label) if inlinable.contains(&label) => {
+                site += 1;
+                let suffix = format!("Inline_{}", site);
+                let exit = label.with_suffix(&format!("Return_{}", suffix));
+
+                for instruction in &bodies[&label] {
+                    out.push(rename(instruction, &suffix, &exit));
+                }
+                out.push(Instruction::Label(exit));
+            }
+            instruction => out.push(instruction),
+        }
+    }
+
+    Program(out)
+}
+
+fn size(body: &[Instruction]) -> usize {
+    body.iter()
+        .filter(|instruction| !matches!(instruction, Instruction::Label(_)))
+        .count()
+}
+
+/// A body that can run off its end would fall into the next function.
+fn ends_with_exit(body: &[Instruction]) -> bool {
+    matches!(
+        body.last(),
+        Some(
+            Instruction::ScopeOut(_)
+                | Instruction::Ret
+                | Instruction::Halt(_)
+                | Instruction::Jmp(_)
+        )
+    )
+}
+
+/// Every `out` of a spliced body becomes a jump to the continuation, including
+/// those meant to close a block.
+fn opens_blocks(body: &[Instruction]) -> bool {
+    body.iter()
+        .any(|instruction| matches!(instruction, Instruction::ScopeIn(_)))
+}
+
+fn calls(body: &[Instruction]) -> impl Iterator<Item = &Label> {
+    body.iter().filter_map(|instruction| match instruction {
+        Instruction::Call(label) => Some(label),
+        _ => None,
+    })
+}
+
+fn is_recursive(label: &Label, bodies: &HashMap<Label, Vec<Instruction>>) -> bool {
+    let mut seen = HashSet::new();
+    let mut queue = calls(&bodies[label]).collect::<Vec<_>>();
+
+    while let Some(callee) = queue.pop() {
+        if callee == label {
+            return true;
+        }
+        if seen.insert(callee) {
+            if let Some(body) = bodies.get(callee) {
+                queue.extend(calls(body));
+            }
+        }
+    }
+
+    false
+}
+
+fn rename(instruction: &Instruction, suffix: &str, exit: &Label) -> Instruction {
+    let mut instruction = match instruction {
+        Instruction::ScopeOut(_) | Instruction::Ret => return Instruction::Jmp(exit.clone()),
+        Instruction::Jmp(label) => Instruction::Jmp(label.with_suffix(suffix)),
+        Instruction::JmpFalse(label, operand) => {
+            Instruction::JmpFalse(label.with_suffix(suffix), operand.clone())
+        }
+        Instruction::JmpFalseOp(label, op, operand1, operand2) => Instruction::JmpFalseOp(
+            label.with_suffix(suffix),
+            *op,
+            operand1.clone(),
+            operand2.clone(),
+        ),
+        Instruction::Label(label) => Instruction::Label(label.with_suffix(suffix)),
+        instruction => instruction.clone(),
+    };
+
+    for operand in instruction.operands_mut() {
+        if let Operand::Id(id) = operand {
+            *id = format!("{}{}_", id, suffix);
+        }
+    }
+
+    if let Some(Target::Id(id)) = instruction.target_mut() {
+        *id = format!("{}{}_", id, suffix);
+    }
+
+    instruction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Vm;
+
+    #[test]
+    fn test_inline_call() {
+        let program = r#"
+            lbl $$Function__main_$$
+            mov push 2
+            call $$Function__double_$$
+            out
+            lbl $$Function__double_$$
+            mov _x_ pop
+            * _x_ 2 push
+            out
+            "#
+        .parse::<Program>()
+        .unwrap();
+
+        let inlined = run(program, 16);
+
+        assert_eq!(
+            inlined.0[..6]
+                .iter()
+                .map(Instruction::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "lbl $$Function__main_$$",
+                "mov push 2",
+                "mov _x_Inline_1_ pop",
+                "* _x_Inline_1_ 2 push",
+                "jmp $$Body__double__Return_Inline_1$$",
+                "lbl $$Body__double__Return_Inline_1$$",
+            ]
+        );
+        assert_eq!(Vm::default().run(&inlined), Ok("4".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_skip_recursive_and_large_functions() {
+        let fibonacci = include_str!("../../test/fibonacci.4km")
+            .parse::<Program>()
+            .unwrap();
+        let fizzbuzz = include_str!("../../test/fizzbuzz.4km")
+            .parse::<Program>()
+            .unwrap();
+
+        let count_calls = |program: &Program| calls(&program.0).count();
+
+        assert_eq!(count_calls(&run(fibonacci, 1000)), 3);
+        assert_eq!(count_calls(&run(fizzbuzz, 4)), 1);
+    }
+
+    #[test]
+    fn test_functions_unchanged() {
+        let source = r#"
+            lbl $$Function__main_$$
+            mov push 2
+            call $$Function__double_$$
+            out
+            lbl $$Function__double_$$
+            mov _x_ pop
+            * _x_ 2 push
+            out
+            "#;
+        let labels = |program: Program| {
+            program
+                .functions()
+                .into_iter()
+                .map(|function| function.label)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            labels(run(source.parse().unwrap(), 16)),
+            labels(source.parse().unwrap())
+        );
+    }
+}
@@ -0,0 +1,110 @@
+mod const_fold;
+mod dce;
+mod inline;
+mod peephole;
+
+use crate::{ir, label::Label, program::Program, vm::MAIN_FN};
+use std::fmt::Display;
+
+#[derive(Debug)]
+pub struct Options {
+    /// Largest callee, in instructions, that gets inlined. `0` disables inlining.
+    pub inline_threshold: usize,
+    /// Also run the SSA pass pipeline from the `ir` module.
+    pub ssa: bool,
+    /// The function the run starts at, which code must be reachable from.
+    pub entry: Label,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            inline_threshold: 24,
+            ssa: false,
+            entry: Label::function(MAIN_FN),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub before: usize,
+    pub after: usize,
+    pub removed: dce::Removed,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Instructions: {} -> {}", self.before, self.after)?;
+        writeln!(
+            f,
+            "Unreachable instructions removed: {}",
+            self.removed.instructions
+        )?;
+        writeln!(f, "Unused labels removed: {}", self.removed.labels)?;
+        write!(
+            f,
+            "Uncalled functions removed: {}",
+            self.removed.functions.len()
+        )?;
+
+        for function in &self.removed.functions {
+            write!(f, "\n  {}", function)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the optimization passes enabled by `-O`.
+pub fn optimize(program: Program, options: &Options) -> (Program, Stats) {
+    let before = program.0.len();
+
+    let program = peephole::run(program);
+    let program = inline::run(program, options.inline_threshold);
+    let program = peephole::run(program);
+    let program = const_fold::run(program);
+    let (program, removed) = dce::run(program, &options.entry);
+    let program = peephole::run(program);
+
+    let program = if options.ssa {
+        let program = ir::optimize(program);
+        let (program, _) = dce::run(program, &options.entry);
+        peephole::run(program)
+    } else {
+        program
+    };
+
+    let stats = Stats {
+        before,
+        after: program.0.len(),
+        removed,
+    };
+
+    (program, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{golden, vm::Vm};
+
+    #[test]
+    fn test_programs_keep_results() {
+        golden::assert_results(|program, input| {
+            let (optimized, _) = optimize(program, &Options::default());
+            Vm::with_input(input).run(&optimized)
+        });
+    }
+
+    #[test]
+    fn test_programs_shrink() {
+        let program = include_str!("../../test/fibonacci.4km")
+            .parse::<Program>()
+            .unwrap();
+        let length = program.0.len();
+
+        let (optimized, _) = optimize(program, &Options::default());
+        assert!(optimized.0.len() < length);
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{instruction::Instruction, operand::Operand, program::Program, target::Target};
+
+/// Rewrites `push`/`pop` pairs emitted by the compiler into direct operands.
+///
+/// Each instruction is appended to the output and then repeatedly fused with
+/// the instruction before it, so `mov push _a_`, `mov push _b_`,
+/// `+ pop pop push`, `mov _x_ pop` collapses into `+ _b_ _a_ _x_`.
+pub fn run(program: Program) -> Program {
+    let mut out: Vec<Instruction> = Vec::with_capacity(program.0.len());
+
+    for instruction in program.0 {
+        let mut instruction = instruction;
+
+        while let Some(fused) = out.last().and_then(|last| fuse(last, &instruction)) {
+            out.pop();
+            instruction = fused;
+        }
+
+        out.push(instruction);
+    }
+
+    Program(out)
+}
+
+fn fuse(first: &Instruction, second: &Instruction) -> Option<Instruction> {
+    match first {
+        // The first `pop` that `second` evaluates receives the pushed value.
+        Instruction::Mov(Target::Push, operand) if *operand != Operand::Pop => {
+            let mut fused = second.clone();
+            let pop = fused
+                .operands_mut()
+                .into_iter()
+                .find(|operand| **operand == Operand::Pop)?;
+            *pop = operand.clone();
+            Some(fused)
+        }
+        // A result pushed only to be stored in a variable is stored directly.
+        _ => match (first.target(), second) {
+            (Some(Target::Push), Instruction::Mov(target @ Target::Id(_), Operand::Pop)) => {
+                let mut fused = first.clone();
+                *fused.target_mut()? = target.clone();
+                Some(fused)
+            }
+            _ => None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimize(source: &str) -> Vec<String> {
+        run(source.parse::<Program>().unwrap())
+            .0
+            .iter()
+            .map(Instruction::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_binary_operation() {
+        assert_eq!(
+            optimize("mov push _a_\nmov push _b_\n- pop pop push\nmov _x_ pop"),
+            vec!["- _b_ _a_ _x_"]
+        );
+    }
+
+    #[test]
+    fn test_operand_after_pop() {
+        assert_eq!(optimize("mov push 1\n+ _i_ pop _i_"), vec!["+ _i_ 1 _i_"]);
+    }
+
+    #[test]
+    fn test_label_blocks_fusion() {
+        assert_eq!(
+            optimize("mov push 1\nlbl $$Loop$$\nmov _x_ pop"),
+            vec!["mov push 1", "lbl $$Loop$$", "mov _x_ pop"]
+        );
+    }
+
+    #[test]
+    fn test_call_arguments_stay_on_stack() {
+        assert_eq!(
+            optimize("mov push _i_\ncall $$Function__f_$$\nmov _x_ pop"),
+            vec!["mov push _i_", "call $$Function__f_$$", "mov _x_ pop"]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    cell::RefCell,
+    fmt::Debug,
+    io::{self, Write},
+    rc::Rc,
+};
+
+/// Where `prn` writes its lines.
+pub trait Output: Debug {
+    fn print_line(&mut self, line: &str) -> Result<(), String>;
+}
+
+impl Default for Box<dyn Output> {
+    fn default() -> Self {
+        Box::new(Stdout)
+    }
+}
+
+#[derive(Debug)]
+pub struct Stdout;
+
+impl Output for Stdout {
+    fn print_line(&mut self, line: &str) -> Result<(), String> {
+        writeln!(io::stdout(), "{}", line).map_err(|e| format!("Failed to write output: {e}"))
+    }
+}
+
+/// Collects the output in memory. Clones share the same buffer.
+#[derive(Debug, Clone, Default)]
+pub struct Buffer(Rc<RefCell<String>>);
+
+impl Buffer {
+    /// The output collected since the last call.
+    pub fn take(&self) -> String {
+        self.0.take()
+    }
+}
+
+impl Output for Buffer {
+    fn print_line(&mut self, line: &str) -> Result<(), String> {
+        let mut buffer = self.0.borrow_mut();
+        buffer.push_str(line);
+        buffer.push('\n');
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+use crate::{
+    instruction::Instruction,
+    label::Label,
+    program::{Program, SourceMap},
+    vm::{Hook, Vm},
+};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+/// Number of hottest instructions listed in the report.
+static TOP_INSTRUCTIONS: usize = 20;
+
+#[derive(Debug, Default, Clone)]
+struct FunctionStats {
+    calls: u64,
+    instructions: u64,
+    inclusive: Duration,
+    exclusive: Duration,
+}
+
+#[derive(Debug)]
+struct Frame {
+    label: Label,
+    /// The labels of all open frames joined by `;`, as in a folded stack.
+    path: String,
+    start: Instant,
+    children: Duration,
+}
+
+/// Counts executed instructions and times function calls.
+#[derive(Debug)]
+pub struct Profiler {
+    counts: Vec<u64>,
+    /// Enclosing function of each instruction.
+    functions: Vec<Option<Label>>,
+    stats: HashMap<Label, FunctionStats>,
+    /// Instructions executed with each call stack.
+    folded: HashMap<String, u64>,
+    stack: Vec<Frame>,
+}
+
+impl Profiler {
+    pub fn new(program: &Program) -> Self {
+        let mut functions = vec![None; program.0.len()];
+        for function in program.functions() {
+            for slot in &mut functions[function.start..function.end] {
+                *slot = Some(function.label.clone());
+            }
+        }
+
+        Self {
+            counts: vec![0; program.0.len()],
+            functions,
+            stats: HashMap::new(),
+            folded: HashMap::new(),
+            stack: vec![],
+        }
+    }
+
+    fn enter(&mut self, label: Label) {
+        let path = match self.stack.last() {
+            Some(frame) => format!("{};{}", frame.path, label),
+            None => label.to_string(),
+        };
+
+        self.stats.entry(label.clone()).or_default().calls += 1;
+        self.stack.push(Frame {
+            label,
+            path,
+            start: Instant::now(),
+            children: Duration::ZERO,
+        });
+    }
+
+    fn exit(&mut self) {
+        let Some(frame) = self.stack.pop() else {
+            return;
+        };
+        let elapsed = frame.start.elapsed();
+        let recursive = self.stack.iter().any(|f| f.label == frame.label);
+
+        let stats = self.stats.entry(frame.label).or_default();
+        stats.exclusive += elapsed.saturating_sub(frame.children);
+        // Time of a recursive call is already part of the outer call.
+        if !recursive {
+            stats.inclusive += elapsed;
+        }
+
+        if let Some(parent) = self.stack.last_mut() {
+            parent.children += elapsed;
+        }
+    }
+
+    /// Closes the frames left open by a run that ended with an error.
+    pub fn finish(&mut self) {
+        while !self.stack.is_empty() {
+            self.exit();
+        }
+    }
+
+    /// With a source map, functions and instructions also list their position.
+    pub fn report(&self, program: &Program, map: Option<&SourceMap>) -> String {
+        let mut out = String::new();
+        let position = |i: usize| {
+            map.and_then(|map| map.position(i))
+                .map_or(String::new(), |position| format!("  ({})", position))
+        };
+        let labels = program.labels();
+
+        let mut functions = self.stats.iter().collect::<Vec<_>>();
+        functions.sort_by(|(a, x), (b, y)| {
+            y.exclusive
+                .cmp(&x.exclusive)
+                .then(a.as_str().cmp(b.as_str()))
+        });
+
+        writeln!(
+            out,
+            "{:>8} {:>12} {:>12} {:>12}  function",
+            "calls", "instructions", "inclusive", "exclusive"
+        )
+        .unwrap();
+        for (label, stats) in functions {
+            writeln!(
+                out,
+                "{:>8} {:>12} {:>12.3?} {:>12.3?}  {}{}",
+                stats.calls,
+                stats.instructions,
+                stats.inclusive,
+                stats.exclusive,
+                label,
+                labels.get(label).map_or(String::new(), |&i| position(i))
+            )
+            .unwrap();
+        }
+
+        let mut opcodes: HashMap<String, u64> = HashMap::new();
+        for (instruction, count) in program.0.iter().zip(&self.counts) {
+            if *count > 0 {
+                *opcodes.entry(instruction.mnemonic()).or_default() += count;
+            }
+        }
+        let mut opcodes = opcodes.into_iter().collect::<Vec<_>>();
+        opcodes.sort_by(|(a, x), (b, y)| y.cmp(x).then(a.as_str().cmp(b.as_str())));
+
+        writeln!(out, "\n{:>8}  opcode", "count").unwrap();
+        for (opcode, count) in opcodes {
+            writeln!(out, "{:>8}  {}", count, opcode).unwrap();
+        }
+
+        let mut hottest = (0..self.counts.len())
+            .filter(|&i| self.counts[i] > 0)
+            .collect::<Vec<_>>();
+        hottest.sort_by(|&a, &b| self.counts[b].cmp(&self.counts[a]).then(a.cmp(&b)));
+
+        writeln!(out, "\n{:>8} {:>5}  instruction", "count", "index").unwrap();
+        for i in hottest.into_iter().take(TOP_INSTRUCTIONS) {
+            writeln!(
+                out,
+                "{:>8} {:>5}  {}{}",
+                self.counts[i],
+                i,
+                program.0[i],
+                position(i)
+            )
+            .unwrap();
+        }
+
+        out
+    }
+
+    /// Stacks in the folded format read by flamegraph tools, weighted by the
+    /// number of instructions executed.
+    pub fn folded(&self) -> String {
+        let mut stacks = self.folded.iter().collect::<Vec<_>>();
+        stacks.sort();
+
+        stacks
+            .into_iter()
+            .map(|(stack, count)| format!("{} {}\n", stack, count))
+            .collect()
+    }
+}
+
+impl Hook for Profiler {
+    fn step(&mut self, vm: &Vm, program: &Program, i: usize) {
+        let Some(instruction) = program.0.get(i) else {
+            return;
+        };
+
+        if self.stack.is_empty() {
+            if let Some(label) = self.functions[i].clone() {
+                self.enter(label);
+            }
+        }
+
+        self.counts[i] += 1;
+        if let Some(frame) = self.stack.last() {
+            self.stats.get_mut(&frame.label).unwrap().instructions += 1;
+            *self.folded.entry(frame.path.clone()).or_default() += 1;
+        }
+
+        match instruction {
+            Instruction::Call(label) => self.enter(label.clone()),
+            Instruction::ScopeOut(_) if vm.in_block() => {}
+            Instruction::ScopeOut(_) | Instruction::Ret => self.exit(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(source: &str) -> (Program, Profiler) {
+        let program = source.parse::<Program>().unwrap();
+        let mut profiler = Profiler::new(&program);
+        Vm::default()
+            .run_with_hook(&program, &mut profiler)
+            .unwrap();
+        profiler.finish();
+        (program, profiler)
+    }
+
+    #[test]
+    fn test_counts() {
+        let (_, profiler) = profile(include_str!("../test/factorial.4km"));
+        let main = Label::function("_main_");
+        let factorial = Label::function("_factorial_");
+
+        assert_eq!(profiler.stats[&main].calls, 1);
+        assert_eq!(profiler.stats[&factorial].calls, 11);
+        assert!(profiler.stats[&main].inclusive >= profiler.stats[&factorial].inclusive);
+        assert_eq!(
+            profiler.counts.iter().sum::<u64>(),
+            profiler.stats.values().map(|s| s.instructions).sum::<u64>()
+        );
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        let (_, profiler) = profile(include_str!("../test/factorial.4km"));
+        let folded = profiler.folded();
+
+        assert!(folded
+            .lines()
+            .any(|line| line.starts_with("$$Function__main_$$ ")));
+        assert!(folded.lines().any(|line| line.starts_with(
+            "$$Function__main_$$;$$Function__factorial_$$;$$Function__factorial_$$ "
+        )));
+    }
+
+    #[test]
+    fn test_report() {
+        let (program, profiler) = profile(include_str!("../test/fibonacci.4km"));
+        let report = profiler.report(&program, None);
+
+        assert!(report.contains("$$Function__fib_$$"));
+        assert!(report.lines().any(|line| line.trim_end().ends_with("call")));
+    }
+
+    #[test]
+    fn test_report_positions() {
+        let source = ".loc main.4ml 1\nlbl $$Function__main_$$\n.loc main.4ml 2 3\nmov push 1\nout";
+        let (program, map) = Program::parse_with_source_map(source, "main.4km").unwrap();
+        let mut profiler = Profiler::new(&program);
+        Vm::default()
+            .run_with_hook(&program, &mut profiler)
+            .unwrap();
+        let report = profiler.report(&program, Some(&map));
+
+        assert!(report.contains("$$Function__main_$$  (main.4ml:1)"));
+        assert!(report.contains("mov push 1  (main.4ml:2:3)"));
+    }
+}
@@ -1,23 +1,231 @@
-use crate::instruction::Instruction;
-use std::str::FromStr;
+use crate::{instruction::Instruction, label::Label};
+use std::{collections::HashMap, fmt::Display, str::FromStr};
 
 #[derive(Debug)]
 pub struct Program(pub Vec<Instruction>);
 
+/// A position in the source the program was compiled from, as given by a
+/// `.loc <file> <line> [<column>]` directive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: Option<usize>,
+}
+
+/// Where each instruction of a program came from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceMap {
+    /// The `.4km` file.
+    pub path: String,
+    /// 1-based line of each instruction in `path`.
+    pub lines: Vec<usize>,
+    /// The last `.loc` before each instruction.
+    pub locations: Vec<Option<Location>>,
+}
+
+/// A `$$Function_...$$` label and the instructions up to the next one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub label: Label,
+    pub start: usize,
+    pub end: usize,
+}
+
 impl FromStr for Program {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        s.lines()
-            .map(|line| line.trim())
-            .enumerate()
-            .filter(|(_, line)| !line.is_empty() && !line.starts_with("//"))
-            .map(|(i, line)| {
-                line.parse::<Instruction>().map_err(|e| {
-                    format!("Error while parsing instruction on line {}: {}", i + 1, e)
-                })
-            })
-            .collect::<Result<Vec<_>, _>>()
-            .map(Program)
+        Self::parse_with_source_map(s, "").map(|(program, _)| program)
+    }
+}
+
+impl FromStr for Location {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid location: {}", s);
+        let number = |word: &str| word.parse::<usize>().map_err(|_| invalid());
+
+        match s.split_whitespace().collect::<Vec<_>>()[..] {
+            [file, line] => Ok(Self {
+                file: file.to_string(),
+                line: number(line)?,
+                column: None,
+            }),
+            [file, line, column] => Ok(Self {
+                file: file.to_string(),
+                line: number(line)?,
+                column: Some(number(column)?),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.column {
+            Some(column) => write!(f, "{}:{}:{}", self.file, self.line, column),
+            None => write!(f, "{}:{}", self.file, self.line),
+        }
+    }
+}
+
+impl SourceMap {
+    /// The map of a program made of the instructions at `origin`.
+    pub fn select(&self, origin: &[usize]) -> Self {
+        Self {
+            path: self.path.clone(),
+            lines: origin.iter().map(|&i| self.lines[i]).collect(),
+            locations: origin.iter().map(|&i| self.locations[i].clone()).collect(),
+        }
+    }
+
+    /// The `.loc` location of instruction `i`, or else its line in the `.4km`.
+    pub fn location(&self, i: usize) -> Option<Location> {
+        match self.locations.get(i)? {
+            Some(location) => Some(location.clone()),
+            None => Some(Location {
+                file: self.path.clone(),
+                line: self.lines[i],
+                column: None,
+            }),
+        }
+    }
+
+    pub fn position(&self, i: usize) -> Option<String> {
+        self.location(i).map(|location| location.to_string())
+    }
+}
+
+impl Program {
+    /// Parses `s`, read from `path`, along with where each instruction came
+    /// from.
+    pub fn parse_with_source_map(s: &str, path: &str) -> Result<(Self, SourceMap), String> {
+        let mut instructions = vec![];
+        let mut map = SourceMap {
+            path: path.to_string(),
+            ..Default::default()
+        };
+        let mut location = None;
+
+        for (i, line) in s.lines().map(|line| line.trim()).enumerate() {
+            let error = |e| format!("Error while parsing instruction on line {}: {}", i + 1, e);
+
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+
+            if let Some(rest) = line
+                .strip_prefix(".loc")
+                .filter(|rest| rest.starts_with(char::is_whitespace))
+            {
+                location = Some(rest.trim().parse::<Location>().map_err(error)?);
+                continue;
+            }
+
+            instructions.push(line.parse::<Instruction>().map_err(error)?);
+            map.lines.push(i + 1);
+            map.locations.push(location.clone());
+        }
+
+        Ok((Program(instructions), map))
+    }
+
+    /// A hash of the instructions that stays the same across builds, unlike
+    /// `DefaultHasher`. Comments, blank lines and `.loc` do not affect it.
+    pub fn fingerprint(&self) -> u64 {
+        // 64-bit FNV-1a.
+        let mut hash: u64 = 0xcbf29ce484222325;
+
+        for instruction in &self.0 {
+            for byte in instruction.to_string().bytes().chain([b'\n']) {
+                hash ^= byte as u64;
+                hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+
+        hash
+    }
+
+    pub fn labels(&self) -> HashMap<&Label, usize> {
+        let mut labels = HashMap::new();
+
+        for (i, instruction) in self.0.iter().enumerate() {
+            if let Instruction::Label(label) = instruction {
+                labels.entry(label).or_insert(i);
+            }
+        }
+
+        labels
+    }
+
+    pub fn functions(&self) -> Vec<Function> {
+        let mut functions: Vec<Function> = vec![];
+
+        for (i, instruction) in self.0.iter().enumerate() {
+            if let Instruction::Label(label) = instruction {
+                if label.is_function() {
+                    if let Some(last) = functions.last_mut() {
+                        last.end = i;
+                    }
+                    functions.push(Function {
+                        label: label.clone(),
+                        start: i,
+                        end: self.0.len(),
+                    });
+                }
+            }
+        }
+
+        functions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SOURCE: &str = r#"
+        lbl $$Function__main_$$
+        .loc fib.4ml 12 5
+        mov push 1
+        // comment
+        .loc fib.4ml 13
+        out
+    "#;
+
+    #[test]
+    fn test_source_map() {
+        let (program, map) = Program::parse_with_source_map(SOURCE, "fib.4km").unwrap();
+
+        assert_eq!(program.0.len(), 3);
+        assert_eq!(map.lines, vec![2, 4, 7]);
+        assert_eq!(map.position(0).unwrap(), "fib.4km:2");
+        assert_eq!(map.position(1).unwrap(), "fib.4ml:12:5");
+        assert_eq!(map.position(2).unwrap(), "fib.4ml:13");
+        assert_eq!(map.position(3), None);
+
+        let selected = map.select(&[0, 2]);
+        assert_eq!(selected.lines, vec![2, 7]);
+        assert_eq!(selected.position(1).unwrap(), "fib.4ml:13");
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let program = "lbl $$Function__main_$$\nmov push 1\nout";
+        let fingerprint = |s: &str| s.parse::<Program>().unwrap().fingerprint();
+
+        assert_eq!(fingerprint(program), fingerprint(SOURCE));
+        assert_ne!(fingerprint(program), fingerprint("mov push 1\nout"));
+    }
+
+    #[test]
+    fn test_invalid_location() {
+        assert_eq!(
+            ".loc fib.4ml twelve".parse::<Program>().unwrap_err(),
+            "Error while parsing instruction on line 1: Invalid location: fib.4ml twelve"
+        );
     }
 }
@@ -0,0 +1,425 @@
+use crate::{
+    input::Input, instruction::Instruction, label::Label, loader, op::Op, operand::Operand,
+    program::Program, target::Target, value::Value,
+};
+use std::collections::HashMap;
+
+/// A jump destination, or the label that could not be found.
+type Address = Result<usize, Label>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Src {
+    Reg(usize),
+    Const(Value),
+    Pop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dst {
+    Reg(usize),
+    Push,
+}
+
+/// Instructions with variables resolved to frame slots and labels resolved to
+/// addresses, so that execution needs no lookups by name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Code {
+    Mov(Dst, Src),
+    Unary(Op, Src, Dst),
+    Binary(Op, Src, Src, Dst),
+    Jmp(Address),
+    JmpFalse(Address, Src),
+    JmpFalseOp(Address, Op, Src, Src),
+    Print(Src),
+    Read,
+    Call(Address),
+    In,
+    Out,
+    Ret,
+    Halt(Src),
+}
+
+#[derive(Debug)]
+pub struct RegisterProgram {
+    pub code: Vec<Code>,
+    /// Index of each instruction in the original `Program`.
+    pub origin: Vec<usize>,
+    /// Variable name of each frame slot.
+    pub names: Vec<String>,
+    pub entry: usize,
+    len: usize,
+}
+
+impl RegisterProgram {
+    /// Loads and translates `program`, which starts at the function labelled
+    /// `entry`.
+    pub fn translate(program: &Program, entry: &Label) -> Result<Self, String> {
+        let image = loader::load(program, entry)?;
+
+        let mut addresses = vec![0; program.0.len()];
+        let mut origin = vec![];
+
+        for (i, instruction) in program.0.iter().enumerate() {
+            addresses[i] = origin.len();
+            if !matches!(instruction, Instruction::Label(_)) {
+                origin.push(i);
+            }
+        }
+
+        let labels = program.labels();
+        let address = |label: &Label| -> Address {
+            labels
+                .get(label)
+                .map(|&i| addresses[i])
+                .ok_or_else(|| label.clone())
+        };
+
+        let mut slots: HashMap<String, usize> = HashMap::new();
+        let mut names = vec![];
+        let mut slot = |id: &String| {
+            *slots.entry(id.clone()).or_insert_with(|| {
+                names.push(id.clone());
+                names.len() - 1
+            })
+        };
+
+        let src = |operand: &Operand, slot: &mut dyn FnMut(&String) -> usize| match operand {
+            Operand::Id(id) => Src::Reg(slot(id)),
+            Operand::Value(value) => Src::Const(value.clone()),
+            Operand::Pop => Src::Pop,
+        };
+        let dst = |target: &Target, slot: &mut dyn FnMut(&String) -> usize| match target {
+            Target::Id(id) => Dst::Reg(slot(id)),
+            Target::Push => Dst::Push,
+        };
+
+        let mut code = Vec::with_capacity(origin.len());
+
+        for &i in &origin {
+            let instruction = &program.0[i];
+
+            code.push(if let Some(op) = Op::from_instruction(instruction) {
+                let operands = instruction.operands();
+                let target = dst(instruction.target().unwrap(), &mut slot);
+                match operands[..] {
+                    [operand] => Code::Unary(op, src(operand, &mut slot), target),
+                    [operand1, operand2] => Code::Binary(
+                        op,
+                        src(operand1, &mut slot),
+                        src(operand2, &mut slot),
+                        target,
+                    ),
+                    _ => unreachable!(),
+                }
+            } else {
+                match instruction {
+                    Instruction::Mov(target, operand) => {
+                        let operand = src(operand, &mut slot);
+                        Code::Mov(dst(target, &mut slot), operand)
+                    }
+                    Instruction::Jmp(label) => Code::Jmp(address(label)),
+                    Instruction::JmpFalse(label, operand) => {
+                        Code::JmpFalse(address(label), src(operand, &mut slot))
+                    }
+                    Instruction::JmpFalseOp(label, op, operand1, operand2) => Code::JmpFalseOp(
+                        address(label),
+                        *op,
+                        src(operand1, &mut slot),
+                        src(operand2, &mut slot),
+                    ),
+                    Instruction::Print(operand) => Code::Print(src(operand, &mut slot)),
+                    Instruction::Push(operand) => Code::Mov(Dst::Push, src(operand, &mut slot)),
+                    Instruction::Pop(target) => Code::Mov(dst(target, &mut slot), Src::Pop),
+                    Instruction::Read => Code::Read,
+                    Instruction::Call(label) => Code::Call(address(label)),
+                    Instruction::ScopeIn(_) => Code::In,
+                    Instruction::ScopeOut(_) => Code::Out,
+                    Instruction::Ret => Code::Ret,
+                    Instruction::Halt(operand) => Code::Halt(src(operand, &mut slot)),
+                    _ => unreachable!(),
+                }
+            });
+        }
+
+        let entry = addresses[image.entry().start];
+
+        Ok(Self {
+            code,
+            origin,
+            names,
+            entry,
+            len: program.0.len(),
+        })
+    }
+}
+
+type Frame = Vec<Option<Value>>;
+
+/// An execution engine over `RegisterProgram` with the semantics of `Vm`.
+#[derive(Debug, Default)]
+pub struct RegisterVm {
+    value_stack: Vec<Value>,
+    call_stack: Vec<usize>,
+    /// The slots of each call.
+    frames: Vec<Frame>,
+    /// For each open block, the depth of the call that opened it and the
+    /// length `declared` had then.
+    blocks: Vec<(usize, usize)>,
+    /// Slots first set inside an open block, which `out` clears again.
+    declared: Vec<usize>,
+    input: Box<dyn Input>,
+}
+
+impl RegisterVm {
+    pub fn with_input(input: Box<dyn Input>) -> Self {
+        Self {
+            input,
+            ..Default::default()
+        }
+    }
+
+    /// Runs `program` from its entry, which receives `args` like a call would.
+    pub fn run(&mut self, program: &RegisterProgram, args: Vec<Value>) -> Result<Value, String> {
+        let mut i = program.entry;
+
+        self.value_stack.extend(args);
+        self.frames.push(vec![None; program.names.len()]);
+
+        loop {
+            let Some(code) = program.code.get(i) else {
+                let index = program.origin.get(i).copied().unwrap_or(program.len);
+                return Err(format!("No instruction found at index {}", index));
+            };
+
+            log::debug!("{}: {:?}", program.origin[i], code);
+            i += 1;
+
+            match code {
+                Code::Mov(dst, src) => {
+                    let value = self.get(program, src)?;
+                    self.set(*dst, value);
+                }
+                Code::Binary(op, src1, src2, dst) => {
+                    let value1 = self.get(program, src1)?;
+                    let value2 = self.get(program, src2)?;
+                    let result = op.evaluate(&[value1, value2])?;
+                    self.set(*dst, result);
+                }
+                Code::Unary(op, src, dst) => {
+                    let value = self.get(program, src)?;
+                    let result = op.evaluate(&[value])?;
+                    self.set(*dst, result);
+                }
+                Code::Jmp(address) => i = resolve(address)?,
+                Code::JmpFalse(address, src) => {
+                    if !self.get(program, src)?.is_truthy() {
+                        i = resolve(address)?;
+                    }
+                }
+                Code::JmpFalseOp(address, op, src1, src2) => {
+                    let value1 = self.get(program, src1)?;
+                    let value2 = self.get(program, src2)?;
+                    if !op.evaluate(&[value1, value2])?.is_truthy() {
+                        i = resolve(address)?;
+                    }
+                }
+                Code::Print(src) => println!("{}", self.get(program, src)?),
+                Code::Read => {
+                    let line = self.input.read_line()?;
+                    self.set(Dst::Push, Value::from_str(line.trim())?);
+                }
+                Code::Call(address) => {
+                    let target = resolve(address)?;
+                    self.call_stack.push(i);
+                    self.frames.push(vec![None; program.names.len()]);
+                    i = target;
+                }
+                Code::Halt(src) => return self.get(program, src),
+                Code::In => self.blocks.push((self.frames.len(), self.declared.len())),
+                Code::Out if self.in_block() => {
+                    // Like `Vm`, keep assignments to the enclosing scope's
+                    // variables and drop the ones the block declared.
+                    let (_, declared) = self.blocks.pop().unwrap();
+                    self.clear_declared(declared);
+                }
+                Code::Out | Code::Ret => {
+                    while self.in_block() {
+                        let (_, declared) = self.blocks.pop().unwrap();
+                        self.clear_declared(declared);
+                    }
+                    self.frames.pop();
+                    if self.frames.is_empty() {
+                        return self
+                            .value_stack
+                            .pop()
+                            .ok_or_else(|| "No value stack found".to_string());
+                    }
+                    i = self
+                        .call_stack
+                        .pop()
+                        .ok_or_else(|| "No call stack found".to_string())?;
+                }
+            }
+        }
+    }
+
+    /// Whether the running call has a block open.
+    fn in_block(&self) -> bool {
+        self.blocks
+            .last()
+            .is_some_and(|(depth, _)| *depth == self.frames.len())
+    }
+
+    /// Clears the slots declared since `declared` had that length.
+    fn clear_declared(&mut self, declared: usize) {
+        let frame = self.frames.last_mut().unwrap();
+        for slot in self.declared.drain(declared..) {
+            frame[slot] = None;
+        }
+    }
+
+    fn get(&mut self, program: &RegisterProgram, src: &Src) -> Result<Value, String> {
+        match src {
+            Src::Reg(slot) => {
+                let frame = self.frames.last().unwrap();
+                frame[*slot].clone().ok_or_else(|| {
+                    format!(
+                        "Variable {} not found in scope: {:#?}",
+                        program.names[*slot],
+                        frame
+                            .iter()
+                            .zip(&program.names)
+                            .filter(|(value, _)| value.is_some())
+                            .map(|(_, name)| name)
+                            .collect::<Vec<_>>()
+                    )
+                })
+            }
+            Src::Const(value) => Ok(value.clone()),
+            Src::Pop => self
+                .value_stack
+                .pop()
+                .ok_or_else(|| "Cannot pop. The value stack is empty".to_string()),
+        }
+    }
+
+    fn set(&mut self, dst: Dst, value: Value) {
+        match dst {
+            Dst::Reg(slot) => {
+                let in_block = self.in_block();
+                let frame = self.frames.last_mut().unwrap();
+                if in_block && frame[slot].is_none() {
+                    self.declared.push(slot);
+                }
+                frame[slot] = Some(value);
+            }
+            Dst::Push => self.value_stack.push(value),
+        }
+    }
+}
+
+fn resolve(address: &Address) -> Result<usize, String> {
+    address
+        .clone()
+        .map_err(|label| format!("No label found for {}", label))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        golden,
+        vm::{Vm, MAIN_FN},
+    };
+    use std::time::Instant;
+
+    fn run(source: &str) -> (Result<Value, String>, Result<Value, String>) {
+        let program = source.parse::<Program>().unwrap();
+        let expected = Vm::default().run(&program);
+        let actual = RegisterProgram::translate(&program, &Label::function(MAIN_FN))
+            .and_then(|program| RegisterVm::default().run(&program, vec![]));
+        (expected, actual)
+    }
+
+    #[test]
+    fn test_programs_keep_results() {
+        golden::assert_results(|program, input| {
+            let program = RegisterProgram::translate(&program, &Label::function(MAIN_FN))?;
+            RegisterVm::with_input(input).run(&program, vec![])
+        });
+    }
+
+    #[test]
+    fn test_programs_match_stack_engine() {
+        for source in [
+            "lbl $$Function__main_$$\ncall $$Function__f_$$\nout\nlbl $$Function__f_$$\nhalt \"f\"",
+            "lbl $$Function__f_$$\npop _n_\n* _n_ 2 push\nret\nlbl $$Function__main_$$\npush 4\ncall $$Function__f_$$\npop push\nret",
+            "lbl $$Function__f_$$\npop _n_\nin\n+ _n_ 1 _n_\nin\nmov _x_ 1\npush _n_\nret\n\
+             lbl $$Function__main_$$\nmov _n_ 10\nin\npush 4\ncall $$Function__f_$$\npop _r_\n\
+             + _r_ _n_ _n_\nout\npush _n_\nout",
+            "lbl $$Function__main_$$\nin\nmov _r_ 1\nout\npush _r_\nout",
+        ] {
+            let (expected, actual) = run(source);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    #[test]
+    fn test_errors_match_stack_engine() {
+        for source in [
+            "lbl $$Function__main_$$\njmp $$Nowhere$$",
+            "lbl $$Function__main_$$\n- 1 \"a\" push\nout",
+            "lbl $$Function__main_$$\nmov push 1",
+            "lbl $$Function__f_$$\nout",
+            "lbl $$Function__main_$$\nhalt pop",
+            "lbl $$Function__main_$$\nin\nmov _x_ 1\nout\npush _x_\nout",
+        ] {
+            let (expected, actual) = run(source);
+            assert_eq!(actual, expected);
+        }
+    }
+
+    /// `cargo test --release -- --ignored --nocapture` prints the time each
+    /// engine takes for a loop whose body is a block with many variables.
+    #[test]
+    #[ignore]
+    fn bench_engines() {
+        let names = (0..50).map(|i| format!("_v{}_", i)).collect::<Vec<_>>();
+        let source = format!(
+            r#"
+            lbl $$Function__main_$$
+            {}
+            mov _i_ 0
+            lbl $$Condition_Loop_1$$
+            < 200000 _i_ push
+            jf $$Exit_Loop_1$$ pop
+            in
+            mov _t_ _i_
+            + _v0_ _t_ _v0_
+            + _i_ 1 _i_
+            out
+            jmp $$Condition_Loop_1$$
+            lbl $$Exit_Loop_1$$
+            push _v0_
+            out
+            "#,
+            names
+                .iter()
+                .map(|name| format!("mov {} 0", name))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+        let program = source.parse::<Program>().unwrap();
+
+        let start = Instant::now();
+        let expected = Vm::default().run(&program).unwrap();
+        println!("   stack: {:?}", start.elapsed());
+
+        let start = Instant::now();
+        let translated = RegisterProgram::translate(&program, &Label::function(MAIN_FN)).unwrap();
+        let actual = RegisterVm::default().run(&translated, vec![]).unwrap();
+        println!("register: {:?}", start.elapsed());
+
+        assert_eq!(actual, expected);
+    }
+}
@@ -0,0 +1,257 @@
+use crate::{
+    instruction::Instruction,
+    label::Label,
+    program::Program,
+    snapshot::Snapshot,
+    vm::{Frame, Scope, Vm},
+};
+use std::fs;
+
+/// Frame the session's instructions run in, so that they never return
+/// from main.
+static SESSION: &str = "$$Repl$$";
+
+/// Runs instructions as they are entered, against a VM that keeps its state
+/// between lines.
+#[derive(Debug)]
+pub struct Repl {
+    /// The functions defined so far. Entered instructions are appended while
+    /// they run.
+    program: Program,
+    vm: Vm,
+    /// Whether the lines entered are added to a function rather than run.
+    defining: bool,
+}
+
+impl Repl {
+    pub fn new(mut vm: Vm) -> Self {
+        vm.restore(Snapshot {
+            fingerprint: 0,
+            ip: 0,
+            value_stack: vec![],
+            call_stack: vec![Frame {
+                callee: Label::new(SESSION),
+                call_site: 0,
+                return_to: 0,
+                scope: 0,
+            }],
+            scope_stack: vec![Scope::new()],
+        });
+
+        Self {
+            program: Program(vec![]),
+            vm,
+            defining: false,
+        }
+    }
+
+    pub fn prompt(&self) -> &'static str {
+        if self.defining {
+            "... "
+        } else {
+            "> "
+        }
+    }
+
+    /// Handles one entered line and returns what to print.
+    pub fn line(&mut self, line: &str) -> Result<String, String> {
+        let line = line.trim();
+
+        if let Some(path) = line.strip_prefix(":load ") {
+            return self.load(path.trim());
+        }
+        if line.starts_with("//") || (line.is_empty() && !self.defining) {
+            return Ok(String::new());
+        }
+        if line.is_empty() {
+            self.defining = false;
+            return Ok("Defined".to_string());
+        }
+
+        let instruction = line.parse::<Instruction>()?;
+        if let Instruction::Label(label) = &instruction {
+            self.check_undefined(label)?;
+        }
+
+        match instruction {
+            _ if self.defining => self.program.0.push(instruction),
+            Instruction::Label(label) if label.is_function() => {
+                self.program.0.push(Instruction::Label(label));
+                self.defining = true;
+            }
+            Instruction::ScopeOut(_) if self.vm.in_block() => {
+                self.run(instruction)?;
+                return Ok(self.state());
+            }
+            Instruction::ScopeOut(_) | Instruction::Ret => {
+                return Err(format!(
+                    "`{}` can only return from a function",
+                    instruction.mnemonic()
+                ))
+            }
+            instruction => {
+                self.run(instruction)?;
+                return Ok(self.state());
+            }
+        }
+
+        Ok(String::new())
+    }
+
+    /// Adds the functions of a `.4km` file to the session.
+    fn load(&mut self, path: &str) -> Result<String, String> {
+        let source =
+            fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let program = source.parse::<Program>()?;
+
+        for instruction in &program.0 {
+            if let Instruction::Label(label) = instruction {
+                self.check_undefined(label)?;
+            }
+        }
+        let functions = program
+            .functions()
+            .into_iter()
+            .map(|function| function.label.to_string())
+            .collect::<Vec<_>>();
+        self.program.0.extend(program.0);
+
+        Ok(format!("Loaded {}", functions.join(", ")))
+    }
+
+    fn check_undefined(&self, label: &Label) -> Result<(), String> {
+        if self.program.0.contains(&Instruction::Label(label.clone())) {
+            return Err(format!("Label {} is already defined", label));
+        }
+
+        Ok(())
+    }
+
+    /// Runs `instruction`, including any call it makes, and leaves the VM as
+    /// it was if that fails.
+    fn run(&mut self, instruction: Instruction) -> Result<(), String> {
+        let i = self.program.0.len();
+        self.program.0.push(instruction);
+
+        let mut before = self.vm.snapshot(&self.program);
+        before.ip = i;
+        self.vm.restore(before.clone());
+
+        let mut result = Ok(());
+        loop {
+            if let Err(e) = self.vm.step(&self.program, &mut ()) {
+                self.vm.restore(before);
+                result = Err(e);
+                break;
+            }
+            if self.vm.depth() == 1 {
+                break;
+            }
+        }
+
+        self.program.0.truncate(i);
+        result
+    }
+
+    /// The value stack, bottom first, and the session's variables.
+    fn state(&self) -> String {
+        let stack = self
+            .vm
+            .stack()
+            .iter()
+            .map(|value| value.literal())
+            .collect::<Vec<_>>();
+
+        let mut scope = self
+            .vm
+            .scopes()
+            .last()
+            .into_iter()
+            .flatten()
+            .map(|(name, value)| format!("{} = {}", name, value.literal()))
+            .collect::<Vec<_>>();
+        scope.sort();
+
+        format!("stack: [{}]\nscope: {}", stack.join(", "), scope.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Buffer;
+
+    fn repl() -> (Repl, Buffer) {
+        let output = Buffer::default();
+        let vm = Vm::with_io(Box::default(), Box::new(output.clone()));
+        (Repl::new(vm), output)
+    }
+
+    #[test]
+    fn test_runs_each_line() {
+        let (mut repl, output) = repl();
+
+        assert_eq!(repl.line("mov _x_ 2").unwrap(), "stack: []\nscope: _x_ = 2");
+        assert_eq!(
+            repl.line("mov push \"a\"").unwrap(),
+            "stack: [\"a\"]\nscope: _x_ = 2"
+        );
+        repl.line("prn pop").unwrap();
+        assert_eq!(output.take(), "a\n");
+    }
+
+    #[test]
+    fn test_define_and_call() {
+        let (mut repl, _) = repl();
+
+        for line in [
+            "lbl $$Function__double_$$",
+            "mov _n_ pop",
+            "* _n_ 2 push",
+            "out",
+        ] {
+            assert_eq!(repl.line(line).unwrap(), "");
+            assert_eq!(repl.prompt(), "... ");
+        }
+        assert_eq!(repl.line("").unwrap(), "Defined");
+        assert_eq!(repl.prompt(), "> ");
+
+        repl.line("mov push 21").unwrap();
+        assert_eq!(
+            repl.line("call $$Function__double_$$").unwrap(),
+            "stack: [42]\nscope: "
+        );
+        assert!(repl.line("lbl $$Function__double_$$").is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let (mut repl, output) = repl();
+
+        assert_eq!(
+            repl.line(":load test/factorial.4km").unwrap(),
+            "Loaded $$Function__main_$$, $$Function__factorial_$$"
+        );
+        repl.line("mov push 5").unwrap();
+        assert_eq!(
+            repl.line("call $$Function__factorial_$$").unwrap(),
+            "stack: [120]\nscope: "
+        );
+        repl.line("call $$Function__main_$$").unwrap();
+        assert_eq!(output.take(), "3628800\n");
+    }
+
+    #[test]
+    fn test_error_keeps_state() {
+        let (mut repl, _) = repl();
+        repl.line("mov push 1").unwrap();
+
+        assert!(repl.line("- \"a\" pop push").is_err());
+        assert!(repl.line("call $$Function__missing_$$").is_err());
+        assert!(repl.line("out").is_err());
+        assert_eq!(
+            repl.line("mov _x_ pop").unwrap(),
+            "stack: []\nscope: _x_ = 1"
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{
+    value::Value,
+    vm::{Frame, Scope},
+};
+use serde_json::{json, Map, Value as Json};
+
+/// Version of the snapshot file format.
+static VERSION: u64 = 1;
+
+/// The state of a paused run, tied to the program it was taken from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub fingerprint: u64,
+    pub ip: usize,
+    pub value_stack: Vec<Value>,
+    pub call_stack: Vec<Frame>,
+    pub scope_stack: Vec<Scope>,
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> String {
+        let frames = self
+            .call_stack
+            .iter()
+            .map(|frame| {
+                json!({
+                    "callee": frame.callee.to_string(),
+                    "call_site": frame.call_site,
+                    "return_to": frame.return_to,
+                    "scope": frame.scope,
+                })
+            })
+            .collect::<Vec<_>>();
+        let scopes = self
+            .scope_stack
+            .iter()
+            .map(|scope| {
+                scope
+                    .iter()
+                    .map(|(name, value)| (name.clone(), value.to_json()))
+                    .collect::<Map<_, _>>()
+            })
+            .collect::<Vec<_>>();
+
+        json!({
+            "version": VERSION,
+            "program": format!("{:016x}", self.fingerprint),
+            "ip": self.ip,
+            "stack": self.value_stack.iter().map(Value::to_json).collect::<Vec<_>>(),
+            "frames": frames,
+            "scopes": scopes,
+        })
+        .to_string()
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let json: Json = serde_json::from_str(s).map_err(|e| format!("Invalid snapshot: {}", e))?;
+        let invalid = |field: &str| format!("Invalid snapshot: bad or missing {}", field);
+        let index = |json: &Json, field: &str| {
+            json.get(field)
+                .and_then(Json::as_u64)
+                .map(|n| n as usize)
+                .ok_or_else(|| invalid(field))
+        };
+        let array = |field: &str| {
+            json.get(field)
+                .and_then(Json::as_array)
+                .ok_or_else(|| invalid(field))
+        };
+
+        let version = json.get("version").and_then(Json::as_u64);
+        if version != Some(VERSION) {
+            return Err(format!(
+                "Unsupported snapshot version: {}",
+                json.get("version").unwrap_or(&Json::Null)
+            ));
+        }
+
+        let fingerprint = json
+            .get("program")
+            .and_then(Json::as_str)
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| invalid("program"))?;
+
+        let value_stack = array("stack")?
+            .iter()
+            .map(Value::from_json)
+            .collect::<Result<_, _>>()?;
+
+        // Snapshots from before blocks have one scope per frame.
+        let call_stack = array("frames")?
+            .iter()
+            .enumerate()
+            .map(|(i, frame)| {
+                Ok(Frame {
+                    callee: frame
+                        .get("callee")
+                        .and_then(Json::as_str)
+                        .ok_or_else(|| invalid("callee"))?
+                        .parse()?,
+                    call_site: index(frame, "call_site")?,
+                    return_to: index(frame, "return_to")?,
+                    scope: match frame.get("scope") {
+                        Some(_) => index(frame, "scope")?,
+                        None => i,
+                    },
+                })
+            })
+            .collect::<Result<_, String>>()?;
+
+        let scope_stack = array("scopes")?
+            .iter()
+            .map(|scope| {
+                scope
+                    .as_object()
+                    .ok_or_else(|| invalid("scopes"))?
+                    .iter()
+                    .map(|(name, value)| Ok((name.clone(), Value::from_json(value)?)))
+                    .collect::<Result<_, String>>()
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            fingerprint,
+            ip: index(&json, "ip")?,
+            value_stack,
+            call_stack,
+            scope_stack,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{program::Program, vm::Vm};
+
+    /// Runs `program` until `ip` reaches `pause`, then resumes from a
+    /// snapshot written at that point.
+    fn run_paused(program: &Program, pause: usize) -> Value {
+        let mut vm = Vm::default();
+        while !vm.is_started() || vm.ip() != pause {
+            assert!(vm.step(program, &mut ()).unwrap().is_none());
+        }
+
+        let snapshot = Snapshot::parse(&vm.snapshot(program).to_json()).unwrap();
+        assert_eq!(snapshot, vm.snapshot(program));
+
+        Vm::resume(snapshot, program, Box::default())
+            .unwrap()
+            .run(program)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_resume_matches_full_run() {
+        let program = include_str!("../test/factorial.4km")
+            .parse::<Program>()
+            .unwrap();
+        let expected = Vm::default().run(&program).unwrap();
+        let call = program
+            .0
+            .iter()
+            .rposition(|i| i.mnemonic() == "call")
+            .unwrap();
+
+        assert_eq!(run_paused(&program, call), expected);
+    }
+
+    #[test]
+    fn test_resume_inside_blocks() {
+        let program = "lbl $$Function__f_$$\nin\nin\npush 2\nret\n\
+                       lbl $$Function__main_$$\nin\ncall $$Function__f_$$\nout\nout"
+            .parse::<Program>()
+            .unwrap();
+
+        assert_eq!(run_paused(&program, 3), Value::Float(2.0));
+    }
+
+    #[test]
+    fn test_frames_without_scope() {
+        let json = r#"{"version": 1, "program": "0", "ip": 0, "stack": [], "scopes": [{}, {}],
+            "frames": [{"callee": "$$Function__main_$$", "call_site": 0, "return_to": 1},
+                       {"callee": "$$Function__f_$$", "call_site": 2, "return_to": 3}]}"#;
+
+        let snapshot = Snapshot::parse(json).unwrap();
+        assert_eq!(
+            snapshot
+                .call_stack
+                .iter()
+                .map(|frame| frame.scope)
+                .collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+    }
+
+    #[test]
+    fn test_values_roundtrip() {
+        let snapshot = Snapshot {
+            fingerprint: u64::MAX,
+            ip: 3,
+            value_stack: vec![Value::Float(f64::NEG_INFINITY), Value::String("a".into())],
+            call_stack: vec![],
+            scope_stack: vec![[("_x_".to_string(), Value::Float(2.5))].into()],
+        };
+
+        assert_eq!(Snapshot::parse(&snapshot.to_json()).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_different_program() {
+        let program = "lbl $$Function__main_$$\nmov push 1\nout"
+            .parse::<Program>()
+            .unwrap();
+        let mut vm = Vm::default();
+        vm.step(&program, &mut ()).unwrap();
+        let mut snapshot = vm.snapshot(&program);
+        snapshot.fingerprint ^= 1;
+
+        let error = Vm::resume(snapshot, &program, Box::default()).unwrap_err();
+        assert!(error.starts_with("The snapshot was taken from a different program"));
+    }
+}
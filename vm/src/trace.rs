@@ -0,0 +1,228 @@
+use crate::{
+    instruction::Instruction,
+    operand::Operand,
+    program::Program,
+    value::Value,
+    vm::{Hook, Vm},
+};
+use serde_json::{json, Map, Value as Json};
+use std::io::Write;
+
+/// Writes one JSON object per executed instruction to `out`.
+pub struct Tracer<W: Write> {
+    out: W,
+    /// The record of the running instruction, the stack depth before it and
+    /// the number of values it pushes.
+    pending: Option<(Map<String, Json>, usize, usize)>,
+    error: Option<String>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            pending: None,
+            error: None,
+        }
+    }
+
+    fn write(&mut self, record: Map<String, Json>) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.out, "{}", Json::Object(record)) {
+                self.error = Some(format!("Failed to write trace: {}", e));
+            }
+        }
+    }
+
+    /// Writes the record of an instruction that failed with `error`, if any,
+    /// and returns the output.
+    pub fn finish(mut self, error: Option<&str>) -> Result<W, String> {
+        if let (Some((mut record, ..)), Some(error)) = (self.pending.take(), error) {
+            record.insert("error".to_string(), json!(error));
+            self.write(record);
+        }
+
+        if let Err(e) = self.out.flush() {
+            self.error
+                .get_or_insert(format!("Failed to write trace: {}", e));
+        }
+
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.out),
+        }
+    }
+}
+
+impl<W: Write> Hook for Tracer<W> {
+    fn step(&mut self, vm: &Vm, program: &Program, i: usize) {
+        let Some(instruction) = program.0.get(i) else {
+            return;
+        };
+
+        let mut stack = vm.stack().iter().rev();
+        let values = instruction
+            .operands()
+            .into_iter()
+            .map(|operand| match operand {
+                Operand::Id(id) => vm.variable(id),
+                Operand::Value(value) => Some(value),
+                Operand::Pop => stack.next(),
+            })
+            .collect::<Vec<_>>();
+
+        let mut record = Map::new();
+        record.insert("index".to_string(), json!(i));
+        record.insert("opcode".to_string(), json!(instruction.mnemonic()));
+        record.insert(
+            "operands".to_string(),
+            instruction
+                .operands()
+                .iter()
+                .map(|operand| operand.to_string())
+                .collect(),
+        );
+        if let Some(target) = instruction.target() {
+            record.insert("target".to_string(), json!(target.to_string()));
+        }
+        if let Some(label) = instruction.label() {
+            record.insert("label".to_string(), json!(label.as_str()));
+        }
+        if let (Instruction::Print(_), [Some(value)]) = (instruction, &values[..]) {
+            record.insert("output".to_string(), json!(value.to_string()));
+        }
+        record.insert(
+            "values".to_string(),
+            values
+                .into_iter()
+                .map(|value| value.map_or(Json::Null, Value::to_json))
+                .collect(),
+        );
+        record.insert("depth".to_string(), json!(vm.depth()));
+
+        let (pops, pushes) = instruction.stack_effect();
+        let stack = vm.stack();
+        record.insert(
+            "popped".to_string(),
+            stack[stack.len().saturating_sub(pops)..]
+                .iter()
+                .map(Value::to_json)
+                .collect(),
+        );
+
+        self.pending = Some((record, stack.len(), pushes));
+    }
+
+    fn after(&mut self, vm: &Vm, _: &Program, _: usize) {
+        let Some((mut record, before, pushes)) = self.pending.take() else {
+            return;
+        };
+
+        let stack = vm.stack();
+        record.insert(
+            "stack_delta".to_string(),
+            json!(stack.len() as i64 - before as i64),
+        );
+        record.insert(
+            "pushed".to_string(),
+            stack[stack.len().saturating_sub(pushes)..]
+                .iter()
+                .map(Value::to_json)
+                .collect(),
+        );
+
+        self.write(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(source: &str) -> Vec<Json> {
+        let program = source.parse::<Program>().unwrap();
+        let mut tracer = Tracer::new(vec![]);
+        let error = Vm::default().run_with_hook(&program, &mut tracer).err();
+        let out = tracer.finish(error.as_deref()).unwrap();
+
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_records() {
+        let records = trace(
+            r#"
+            lbl $$Function__main_$$
+            mov _x_ 2
+            mov push _x_
+            - pop 1 push
+            prn pop
+            mov push "done"
+            out
+            "#,
+        );
+
+        assert_eq!(records.len(), 7);
+        assert_eq!(records[0]["label"], "$$Function__main_$$");
+        assert_eq!(records[2]["values"], json!([2.0]));
+        assert_eq!(records[2]["pushed"], json!([2.0]));
+        assert_eq!(records[3]["opcode"], "-");
+        assert_eq!(records[3]["operands"], json!(["pop", "1"]));
+        assert_eq!(records[3]["values"], json!([2.0, 1.0]));
+        assert_eq!(records[3]["stack_delta"], 0);
+        assert_eq!(records[3]["pushed"], json!([-1.0]));
+        assert_eq!(records[4]["output"], "-1");
+        assert_eq!(records[4]["stack_delta"], -1);
+        assert_eq!(records[6]["depth"], 1);
+    }
+
+    #[test]
+    fn test_stack_instructions() {
+        let records = trace(
+            r#"
+            lbl $$Function__main_$$
+            push 3
+            push 4
+            pop push
+            pop _x_
+            push _x_
+            out
+            "#,
+        );
+
+        assert_eq!(records[1]["popped"], json!([]));
+        assert_eq!(records[1]["pushed"], json!([3.0]));
+        assert_eq!(records[1]["stack_delta"], 1);
+        assert_eq!(records[3]["popped"], json!([4.0]));
+        assert_eq!(records[3]["pushed"], json!([4.0]));
+        assert_eq!(records[3]["stack_delta"], 0);
+        assert_eq!(records[4]["popped"], json!([4.0]));
+        assert_eq!(records[4]["pushed"], json!([]));
+        assert_eq!(records[4]["stack_delta"], -1);
+    }
+
+    #[test]
+    fn test_error_record() {
+        let records = trace("lbl $$Function__main_$$\nmov push _x_\nout");
+        let last = records.last().unwrap();
+
+        assert_eq!(last["index"], 1);
+        assert_eq!(last["values"], json!([null]));
+        assert!(last["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Variable _x_ not found"));
+    }
+
+    #[test]
+    fn test_call_depth() {
+        let records = trace(include_str!("../test/fibonacci.4km"));
+
+        assert!(records.iter().any(|record| record["depth"] == 10));
+        assert_eq!(records.last().unwrap()["opcode"], "out");
+    }
+}
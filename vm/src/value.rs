@@ -1,9 +1,95 @@
-use std::{fmt::Display, str::FromStr};
+use serde_json::{json, Value as Json};
+use std::{
+    cell::{Ref, RefCell},
+    fmt::{Debug, Display},
+    rc::Rc,
+    str::FromStr,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Float(f64),
-    String(String),
+    String(Str),
+}
+
+/// An immutable string whose clones share one buffer. Appending to the
+/// longest string on a buffer extends the buffer in place instead of copying
+/// it, so building a string in a loop takes linear time.
+#[derive(Clone)]
+pub struct Str {
+    buffer: Rc<RefCell<String>>,
+    len: usize,
+}
+
+impl Str {
+    pub fn as_str(&self) -> Ref<'_, str> {
+        Ref::map(self.buffer.borrow(), |buffer| &buffer[..self.len])
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn append(&self, tail: &str) -> Self {
+        if self.buffer.borrow().len() != self.len {
+            return Self::from(format!("{}{}", self, tail));
+        }
+
+        let mut buffer = self.buffer.borrow_mut();
+        buffer.push_str(tail);
+        Self {
+            buffer: self.buffer.clone(),
+            len: buffer.len(),
+        }
+    }
+
+    fn concat(&self, other: &Self) -> Self {
+        if Rc::ptr_eq(&self.buffer, &other.buffer) {
+            let tail = other.as_str().to_string();
+            self.append(&tail)
+        } else {
+            self.append(&other.as_str())
+        }
+    }
+}
+
+impl From<String> for Str {
+    fn from(s: String) -> Self {
+        Self {
+            len: s.len(),
+            buffer: Rc::new(RefCell::new(s)),
+        }
+    }
+}
+
+impl From<&str> for Str {
+    fn from(s: &str) -> Self {
+        Self::from(s.to_string())
+    }
+}
+
+impl PartialEq for Str {
+    fn eq(&self, other: &Self) -> bool {
+        *self.as_str() == *other.as_str()
+    }
+}
+
+impl PartialOrd for Str {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.as_str().partial_cmp(&*other.as_str())
+    }
+}
+
+impl Debug for Str {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&*self.as_str(), f)
+    }
+}
+
+impl Display for Str {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&*self.as_str(), f)
+    }
 }
 
 impl FromStr for Value {
@@ -13,7 +99,7 @@ impl FromStr for Value {
         let s = s.trim();
 
         if (s.starts_with('"') && s.ends_with('"')) || (s.starts_with('\'') && s.ends_with('\'')) {
-            Ok(Self::String(s[1..s.len() - 1].to_string()))
+            Ok(Self::String(s[1..s.len() - 1].into()))
         } else {
             s.parse::<f64>()
                 .map(Self::Float)
@@ -36,12 +122,20 @@ impl Value {
         s.parse()
     }
 
+    /// The value as it would be written in a `.4km` operand.
+    pub fn literal(&self) -> String {
+        match self {
+            Self::Float(f) => f.to_string(),
+            Self::String(s) => format!("\"{}\"", s),
+        }
+    }
+
     pub fn add(&self, other: &Self) -> Result<Self, String> {
         match (self, other) {
             (Self::Float(a), Self::Float(b)) => Ok(Self::Float(a + b)),
-            (Self::String(a), Self::String(b)) => Ok(Self::String(format!("{}{}", a, b))),
-            (Self::String(a), Self::Float(b)) => Ok(Self::String(format!("{}{}", a, b))),
-            (Self::Float(a), Self::String(b)) => Ok(Self::String(format!("{}{}", a, b))),
+            (Self::String(a), Self::String(b)) => Ok(Self::String(a.concat(b))),
+            (Self::String(a), Self::Float(b)) => Ok(Self::String(a.append(&b.to_string()))),
+            (Self::Float(a), Self::String(b)) => Ok(Self::String(format!("{}{}", a, b).into())),
         }
     }
 
@@ -75,13 +169,11 @@ impl Value {
 
     pub fn eq(&self, other: &Self) -> Result<Self, String> {
         match (self, other) {
-            (Self::Float(a), Self::Float(b)) => {
-                Ok(Self::Float(if (a - b).abs() < std::f64::EPSILON {
-                    1.0
-                } else {
-                    0.0
-                }))
-            }
+            (Self::Float(a), Self::Float(b)) => Ok(Self::Float(if (a - b).abs() < f64::EPSILON {
+                1.0
+            } else {
+                0.0
+            })),
             (Self::String(a), Self::String(b)) => Ok(Self::Float(if a == b { 1.0 } else { 0.0 })),
             _ => Err(format!("Cannot eq {:?} and {:?}", self, other)),
         }
@@ -155,6 +247,33 @@ impl Value {
         }
     }
 
+    /// Floats as JSON numbers and strings as JSON strings. Non-finite floats,
+    /// which JSON cannot hold, become `{"float": "NaN"}` and the like.
+    pub fn to_json(&self) -> Json {
+        match self {
+            Self::Float(f) if f.is_finite() => json!(f),
+            Self::Float(f) => json!({ "float": f.to_string() }),
+            Self::String(s) => json!(s.to_string()),
+        }
+    }
+
+    pub fn from_json(json: &Json) -> Result<Self, String> {
+        let float = json
+            .get("float")
+            .and_then(Json::as_str)
+            .and_then(|f| f.parse().ok());
+
+        match (json, float) {
+            (Json::Number(n), _) => n
+                .as_f64()
+                .map(Self::Float)
+                .ok_or_else(|| format!("Invalid number: {}", n)),
+            (Json::String(s), _) => Ok(Self::String(s.as_str().into())),
+            (_, Some(f)) => Ok(Self::Float(f)),
+            _ => Err(format!("Invalid value: {}", json)),
+        }
+    }
+
     pub fn is_truthy(&self) -> bool {
         match self {
             Self::Float(a) => *a != 0.0,
@@ -162,3 +281,90 @@ impl Value {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{program::Program, vm::Vm};
+    use std::time::Instant;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.into())
+    }
+
+    #[test]
+    fn test_append_extends_buffer() {
+        let a = string("ab");
+        let b = a.add(&string("c")).unwrap();
+        let c = b.add(&Value::Float(1.0)).unwrap();
+
+        let (Value::String(a), Value::String(c)) = (&a, &c) else {
+            unreachable!();
+        };
+        assert!(Rc::ptr_eq(&a.buffer, &c.buffer));
+        assert_eq!(a.to_string(), "ab");
+        assert_eq!(b, string("abc"));
+        assert_eq!(c.to_string(), "abc1");
+    }
+
+    #[test]
+    fn test_append_to_shorter_string_copies() {
+        let a = string("ab");
+        let b = a.add(&string("c")).unwrap();
+        let c = a.add(&string("x")).unwrap();
+
+        assert_eq!(b, string("abc"));
+        assert_eq!(c, string("abx"));
+        assert_eq!(a.add(&a).unwrap(), string("abab"));
+        assert!(string("ab").lt(&string("abc")).unwrap().is_truthy());
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        for value in [
+            Value::Float(1.5),
+            Value::Float(f64::INFINITY),
+            string("a \"b\""),
+        ] {
+            assert_eq!(Value::from_json(&value.to_json()).unwrap(), value);
+        }
+
+        let nan = Value::from_json(&Value::Float(f64::NAN).to_json()).unwrap();
+        assert!(matches!(nan, Value::Float(f) if f.is_nan()));
+    }
+
+    /// `cargo test --release -- --ignored --nocapture` prints the time to
+    /// build strings of growing length; it should grow linearly.
+    #[test]
+    #[ignore]
+    fn bench_string_concatenation() {
+        for n in [25_000, 50_000, 100_000, 200_000] {
+            let source = format!(
+                r#"
+                lbl $$Function__main_$$
+                mov _result_ ""
+                mov _i_ 0
+                lbl $$Condition_Loop_1$$
+                < {n} _i_ push
+                jf $$Exit_Loop_1$$ pop
+                mov push _result_
+                mov push "x"
+                + pop pop push
+                mov _result_ pop
+                + _i_ 1 _i_
+                jmp $$Condition_Loop_1$$
+                lbl $$Exit_Loop_1$$
+                mov push _result_
+                out
+                "#
+            );
+            let program = source.parse::<Program>().unwrap();
+
+            let start = Instant::now();
+            let result = Vm::default().run(&program).unwrap();
+            println!("{n:>7}: {:?}", start.elapsed());
+
+            assert!(matches!(result, Value::String(s) if s.to_string().len() == n));
+        }
+    }
+}
@@ -1,7 +1,17 @@
 use crate::{
-    instruction::Instruction, label::Label, program::Program, target::Target, value::Value,
+    history::{Control, Delta},
+    input::Input,
+    instruction::Instruction,
+    label::Label,
+    loader,
+    operand::Operand,
+    output::Output,
+    program::{Program, SourceMap},
+    snapshot::Snapshot,
+    target::Target,
+    value::Value,
 };
-use std::{collections::HashMap, io};
+use std::{collections::HashMap, fmt::Write};
 
 #[derive(Debug)]
 enum VmStep {
@@ -10,61 +20,365 @@ enum VmStep {
     Done(Value),
 }
 
-type Scope = HashMap<String, Value>;
+pub type Scope = HashMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub callee: Label,
+    /// Index of the `call`, or of the label of the entry function.
+    pub call_site: usize,
+    pub return_to: usize,
+    /// Index of the function's own scope in the scope stack. Scopes above it
+    /// are blocks opened with `in`, which `ret` closes along with it.
+    pub scope: usize,
+}
+
+/// The calls active when a run stopped, innermost first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backtrace {
+    /// Each function and the index it was executing.
+    pub frames: Vec<(Label, usize)>,
+    /// Variables of the innermost frame, sorted by name.
+    pub locals: Vec<(String, Value)>,
+}
+
+pub static MAIN_FN: &str = "_main_";
 
-static MAIN_FN: &str = "_main_";
+/// Observes execution, e.g. to profile or trace a run.
+pub trait Hook {
+    /// Called before executing the instruction at index `i`.
+    fn step(&mut self, vm: &Vm, program: &Program, i: usize);
 
-fn label(ty: &str, name: &str) -> Label {
-    Label::new(&format!("$${}_{}$$", ty, name))
+    /// Called after the instruction at index `i` ran without error.
+    fn after(&mut self, _vm: &Vm, _program: &Program, _i: usize) {}
+}
+
+impl Hook for () {
+    fn step(&mut self, _: &Vm, _: &Program, _: usize) {}
+}
+
+impl Hook for Vec<&mut dyn Hook> {
+    fn step(&mut self, vm: &Vm, program: &Program, i: usize) {
+        for hook in self.iter_mut() {
+            hook.step(vm, program, i);
+        }
+    }
+
+    fn after(&mut self, vm: &Vm, program: &Program, i: usize) {
+        for hook in self.iter_mut() {
+            hook.after(vm, program, i);
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Vm {
+    /// Index of the instruction being executed.
+    ip: usize,
     value_stack: Vec<Value>,
-    call_stack: Vec<usize>,
+    call_stack: Vec<Frame>,
     scope_stack: Vec<Scope>,
+    input: Box<dyn Input>,
+    output: Box<dyn Output>,
 }
 
 impl Default for Vm {
     fn default() -> Self {
         Self {
+            ip: 0,
             value_stack: Vec::new(),
             call_stack: Vec::new(),
             scope_stack: vec![Scope::new()],
+            input: Box::default(),
+            output: Box::default(),
         }
     }
 }
 
 impl Vm {
+    pub fn with_input(input: Box<dyn Input>) -> Self {
+        Self {
+            input,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_io(input: Box<dyn Input>, output: Box<dyn Output>) -> Self {
+        Self {
+            input,
+            output,
+            ..Default::default()
+        }
+    }
+
     pub fn run(&mut self, program: &Program) -> Result<Value, String> {
-        let mut i = 0;
-
-        let value = loop {
-            match self.run_instruction(program, i) {
-                Ok(VmStep::Next) => i += 1,
-                Ok(VmStep::Jump(j)) => i = j,
-                Ok(VmStep::Done(value)) => break value,
-                Err(e) => return Err(e),
+        self.run_with_hook(program, &mut ())
+    }
+
+    pub fn run_with_hook(
+        &mut self,
+        program: &Program,
+        hook: &mut dyn Hook,
+    ) -> Result<Value, String> {
+        loop {
+            if let Some(value) = self.step(program, hook)? {
+                return Ok(value);
             }
-        };
+        }
+    }
+
+    /// Runs one instruction, entering main first unless the run was started,
+    /// and returns the result once the entry function returns.
+    pub fn step(
+        &mut self,
+        program: &Program,
+        hook: &mut dyn Hook,
+    ) -> Result<Option<Value>, String> {
+        if !self.is_started() {
+            self.enter(program, &Label::function(MAIN_FN), vec![])?;
+        }
+
+        hook.step(self, program, self.ip);
+        let step = self.run_instruction(program, self.ip)?;
+        hook.after(self, program, self.ip);
+
+        match step {
+            VmStep::Next => self.ip += 1,
+            VmStep::Jump(j) => self.ip = j,
+            VmStep::Done(value) => return Ok(Some(value)),
+        }
+
+        Ok(None)
+    }
+
+    /// Loads `program` and starts the run at the function labelled `entry`,
+    /// as if it had been called with `args`.
+    pub fn enter(
+        &mut self,
+        program: &Program,
+        entry: &Label,
+        args: Vec<Value>,
+    ) -> Result<(), String> {
+        let image = loader::load(program, entry)?;
+        let i = image.entry().start;
+
+        self.value_stack.extend(args);
+        self.call_stack.push(Frame {
+            callee: entry.clone(),
+            call_site: i,
+            return_to: i + 1,
+            scope: self.scope_stack.len() - 1,
+        });
+        self.ip = i;
+        Ok(())
+    }
+
+    /// Whether main or the entry was entered, so that `ip` points into the
+    /// running program.
+    pub fn is_started(&self) -> bool {
+        !self.call_stack.is_empty()
+    }
+
+    pub fn snapshot(&self, program: &Program) -> Snapshot {
+        Snapshot {
+            fingerprint: program.fingerprint(),
+            ip: self.ip,
+            value_stack: self.value_stack.clone(),
+            call_stack: self.call_stack.clone(),
+            scope_stack: self.scope_stack.clone(),
+        }
+    }
+
+    /// A VM that continues a run of `program` from `snapshot`.
+    pub fn resume(
+        snapshot: Snapshot,
+        program: &Program,
+        input: Box<dyn Input>,
+    ) -> Result<Self, String> {
+        if snapshot.fingerprint != program.fingerprint() {
+            return Err(format!(
+                "The snapshot was taken from a different program ({:016x}, now {:016x})",
+                snapshot.fingerprint,
+                program.fingerprint()
+            ));
+        }
+        if snapshot.call_stack.is_empty() || snapshot.scope_stack.is_empty() {
+            return Err("The snapshot was taken before main started".to_string());
+        }
+
+        let mut vm = Self::with_input(input);
+        vm.restore(snapshot);
+        Ok(vm)
+    }
+
+    /// Puts the VM in the state of `snapshot`, keeping its input and output.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.ip = snapshot.ip;
+        self.value_stack = snapshot.value_stack;
+        self.call_stack = snapshot.call_stack;
+        self.scope_stack = snapshot.scope_stack;
+    }
+
+    /// Index of the instruction being executed, or of the one that failed.
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.value_stack
+    }
+
+    /// A variable of the innermost scope.
+    pub fn variable(&self, name: &str) -> Option<&Value> {
+        self.scope_stack.last()?.get(name)
+    }
+
+    /// Number of active calls, counting main.
+    pub fn depth(&self) -> usize {
+        self.call_stack.len()
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.call_stack
+    }
 
-        Ok(value)
+    /// Variables of each frame and of the blocks it opened, innermost last.
+    pub fn scopes(&self) -> &[Scope] {
+        &self.scope_stack
+    }
+
+    /// Reverts `delta`, which must be the last change made to this VM.
+    pub fn undo(&mut self, delta: &Delta) {
+        match &delta.control {
+            Some(Control::Call(_)) => {
+                self.scope_stack.pop();
+                self.call_stack.pop();
+            }
+            Some(Control::Enter) => {
+                self.scope_stack.pop();
+            }
+            Some(Control::Leave(block, scope)) => {
+                self.scope_stack.pop();
+                self.scope_stack.push(scope.clone());
+                self.scope_stack.push(block.clone());
+            }
+            Some(Control::Return(frame, scopes)) => {
+                self.call_stack.extend(frame.clone());
+                self.scope_stack.extend(scopes.iter().cloned());
+            }
+            None => {}
+        }
+
+        if let (Some(write), Some(scope)) = (&delta.write, self.scope_stack.last_mut()) {
+            match &write.old {
+                Some(old) => scope.insert(write.name.clone(), old.clone()),
+                None => scope.remove(&write.name),
+            };
+        }
+
+        let base = self.value_stack.len() - delta.pushed.len();
+        self.value_stack.truncate(base);
+        self.value_stack.extend(delta.popped.iter().cloned());
+        self.ip = delta.ip;
+    }
+
+    /// Applies `delta` again after it was undone.
+    pub fn redo(&mut self, delta: &Delta) {
+        let base = self.value_stack.len() - delta.popped.len();
+        self.value_stack.truncate(base);
+        self.value_stack.extend(delta.pushed.iter().cloned());
+
+        if let (Some(write), Some(scope)) = (&delta.write, self.scope_stack.last_mut()) {
+            scope.insert(write.name.clone(), write.new.clone());
+        }
+
+        match &delta.control {
+            Some(Control::Call(frame)) => {
+                self.call_stack.push(frame.clone());
+                self.push_scope();
+            }
+            Some(Control::Enter) => self.enter_block(),
+            Some(Control::Leave(..)) => self.leave_block(),
+            Some(Control::Return(frame, _)) => {
+                self.leave_function();
+                if frame.is_some() {
+                    self.call_stack.pop();
+                }
+            }
+            None => {}
+        }
+        self.ip = delta.next;
     }
 
     fn push_scope(&mut self) {
         self.scope_stack.push(Scope::new());
     }
 
-    fn pop_scope(&mut self) {
-        self.scope_stack.pop();
+    /// Whether the innermost scope is a block opened with `in` rather than
+    /// the scope of the running function.
+    pub fn in_block(&self) -> bool {
+        self.call_stack
+            .last()
+            .is_some_and(|frame| self.scope_stack.len() > frame.scope + 1)
+    }
+
+    /// Opens a block that starts with the variables of the enclosing scope.
+    fn enter_block(&mut self) {
+        let block = self.scope_stack.last().cloned().unwrap_or_default();
+        self.scope_stack.push(block);
+    }
+
+    /// Closes the innermost block. Variables it declared go away, while
+    /// assignments to variables of the enclosing scope are kept.
+    fn leave_block(&mut self) {
+        let block = self.scope_stack.pop().unwrap_or_default();
+        if let Some(scope) = self.scope_stack.last_mut() {
+            for (name, value) in block {
+                if let Some(outer) = scope.get_mut(&name) {
+                    *outer = value;
+                }
+            }
+        }
+    }
+
+    /// Closes the scope of the running function and every block above it.
+    fn leave_function(&mut self) {
+        let scope = self.call_stack.last().map_or(0, |frame| frame.scope);
+        self.scope_stack.truncate(scope);
     }
 
     fn pop_call_stack(&mut self) -> Result<usize, String> {
         self.call_stack
             .pop()
+            .map(|frame| frame.return_to)
             .ok_or_else(|| "No call stack found".to_string())
     }
 
+    pub fn backtrace(&self) -> Backtrace {
+        let sites = std::iter::once(self.ip)
+            .chain(self.call_stack.iter().rev().map(|frame| frame.call_site));
+
+        let mut locals = self
+            .scope_stack
+            .last()
+            .into_iter()
+            .flatten()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        locals.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        Backtrace {
+            frames: self
+                .call_stack
+                .iter()
+                .rev()
+                .map(|frame| frame.callee.clone())
+                .zip(sites)
+                .collect(),
+            locals,
+        }
+    }
+
     fn pop_value_stack(&mut self) -> Result<Value, String> {
         self.value_stack
             .pop()
@@ -89,23 +403,11 @@ impl Vm {
         use Instruction::*;
         use VmStep::*;
 
-        let main_label = label("Function", MAIN_FN);
-
         let instruction = program
             .0
             .get(i)
             .ok_or_else(|| format!("No instruction found at index {}", i))?;
 
-        if self.is_first_run() {
-            // find main function
-            if let Instruction::Label(label) = instruction {
-                if *label == main_label {
-                    self.call_stack.push(i + 1);
-                }
-            }
-            return Ok(Next);
-        }
-
         log::debug!("{i}: {:?}", instruction);
 
         let Some(scope) = self.scope_stack.last_mut() else {
@@ -114,100 +416,41 @@ impl Vm {
 
         match instruction {
             Call(label) => {
-                self.call_stack.push(i + 1);
+                let target = self.find_label(program, label)?;
+                self.call_stack.push(Frame {
+                    callee: label.clone(),
+                    call_site: i,
+                    return_to: i + 1,
+                    scope: self.scope_stack.len(),
+                });
                 self.push_scope();
-                return Ok(Jump(self.find_label(program, label)?));
+                return Ok(Jump(target));
             }
             Mov(target, operand) => {
                 let value = operand.get_value(scope, &mut self.value_stack)?;
                 target.set_value(value, scope, &mut self.value_stack);
             }
-            Add(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value2.add(&value1)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            Sub(operand1, operand2, target) => {
+            Add(operand1, operand2, target)
+            | Sub(operand1, operand2, target)
+            | Mul(operand1, operand2, target)
+            | Div(operand1, operand2, target)
+            | Mod(operand1, operand2, target)
+            | Eq(operand1, operand2, target)
+            | Neq(operand1, operand2, target)
+            | Less(operand1, operand2, target)
+            | LessEq(operand1, operand2, target)
+            | Greater(operand1, operand2, target)
+            | GreaterEq(operand1, operand2, target)
+            | And(operand1, operand2, target)
+            | Or(operand1, operand2, target) => {
                 let value1 = operand1.get_value(scope, &mut self.value_stack)?;
                 let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value2.sub(&value1)?;
+                let result = instruction.evaluate(&[value1, value2])?;
                 target.set_value(result, scope, &mut self.value_stack);
             }
-            Mul(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value1.mul(&value2)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            Div(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value2.div(&value1)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            Mod(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value2.modulo(&value1)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            Eq(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value1.eq(&value2)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            Neq(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value1.ne(&value2)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            Less(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value2.lt(&value1)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            LessEq(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value2.le(&value1)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            Greater(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value2.gt(&value1)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            GreaterEq(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value2.ge(&value1)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            And(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value1.and(&value2)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            Or(operand1, operand2, target) => {
-                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
-                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
-                let result = value1.or(&value2)?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            Not(operand, target) => {
+            Not(operand, target) | Neg(operand, target) => {
                 let value = operand.get_value(scope, &mut self.value_stack)?;
-                let result = value.not()?;
-                target.set_value(result, scope, &mut self.value_stack);
-            }
-            Neg(operand, target) => {
-                let value = operand.get_value(scope, &mut self.value_stack)?;
-                let result = value.neg()?;
+                let result = instruction.evaluate(&[value])?;
                 target.set_value(result, scope, &mut self.value_stack);
             }
             Jmp(label) => {
@@ -219,24 +462,40 @@ impl Vm {
                     return Ok(Jump(self.find_label(program, label)?));
                 }
             }
+            JmpFalseOp(label, _, operand1, operand2) => {
+                let value1 = operand1.get_value(scope, &mut self.value_stack)?;
+                let value2 = operand2.get_value(scope, &mut self.value_stack)?;
+                if !instruction.evaluate(&[value1, value2])?.is_truthy() {
+                    return Ok(Jump(self.find_label(program, label)?));
+                }
+            }
+            Push(operand) => {
+                let value = operand.get_value(scope, &mut self.value_stack)?;
+                Target::Push.set_value(value, scope, &mut self.value_stack);
+            }
+            Pop(target) => {
+                let value = Operand::Pop.get_value(scope, &mut self.value_stack)?;
+                target.set_value(value, scope, &mut self.value_stack);
+            }
             Read => {
-                let mut input = String::new();
-                io::stdin()
-                    .read_line(&mut input)
-                    .map_err(|e| format!("Failed to read input: {e}"))?;
-                let value = Value::from_str(input.trim())?;
+                let value = Value::from_str(self.input.read_line()?.trim())?;
                 Target::Push.set_value(value, scope, &mut self.value_stack);
             }
+            ScopeIn(_) => self.enter_block(),
             Print(operand) => {
                 let value = operand.get_value(scope, &mut self.value_stack)?;
-                println!("{}", value);
+                self.output.print_line(&value.to_string())?;
             }
-            ScopeOut if self.is_upper_scope() => {
-                self.pop_scope();
+            Halt(operand) => {
+                return Ok(Done(operand.get_value(scope, &mut self.value_stack)?));
+            }
+            ScopeOut(_) if self.in_block() => self.leave_block(),
+            ScopeOut(_) | Ret if self.is_entry_frame() => {
+                self.leave_function();
                 return Ok(Done(self.pop_value_stack()?));
             }
-            ScopeOut => {
-                self.pop_scope();
+            ScopeOut(_) | Ret => {
+                self.leave_function();
                 return Ok(Jump(self.pop_call_stack()?));
             }
             Label(_) => {}
@@ -245,18 +504,42 @@ impl Vm {
         Ok(Next)
     }
 
-    fn is_first_run(&self) -> bool {
-        self.call_stack.is_empty()
+    /// Whether the function returning now is the one the run started in.
+    fn is_entry_frame(&self) -> bool {
+        self.call_stack.len() == 1
     }
+}
+
+impl Backtrace {
+    /// Lists the frames with their positions in `map`, then the locals.
+    pub fn render(&self, map: Option<&SourceMap>) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "Backtrace (innermost first):").unwrap();
+        for (depth, (function, i)) in self.frames.iter().enumerate() {
+            let position = map
+                .and_then(|map| map.position(*i))
+                .map_or(String::new(), |position| format!(" ({})", position));
+            writeln!(out, "  {}: {} at {}{}", depth, function, i, position).unwrap();
+        }
+
+        writeln!(out, "Locals:").unwrap();
+        for (name, value) in &self.locals {
+            writeln!(out, "  {} = {:?}", name, value).unwrap();
+        }
 
-    fn is_upper_scope(&self) -> bool {
-        self.scope_stack.len() == 1
+        out
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{program::Program, value::Value, vm::Vm};
+    use crate::{
+        label::Label,
+        program::Program,
+        value::Value,
+        vm::{Backtrace, Vm},
+    };
 
     #[test]
     fn test_program() {
@@ -317,10 +600,153 @@ mod tests {
 
         assert_eq!(
             result,
-            Value::from_str(
-                format!(r#""{}""#, include_str!("../test/fizzbuzz.txt").trim()).as_str()
-            )
-            .unwrap()
+            Value::from_str(include_str!("../test/fizzbuzz.result").trim()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_enter() {
+        let mut vm = Vm::default();
+        let program = include_str!("../test/fibonacci.4km")
+            .parse::<Program>()
+            .unwrap();
+        vm.enter(
+            &program,
+            &Label::function("_fib_"),
+            vec![Value::Float(20.0)],
+        )
+        .unwrap();
+
+        assert_eq!(vm.run(&program), Ok(Value::Float(6765.0)));
+    }
+
+    #[test]
+    fn test_enter_missing() {
+        let mut vm = Vm::default();
+        let program = include_str!("../test/fibonacci.4km")
+            .parse::<Program>()
+            .unwrap();
+
+        assert_eq!(
+            vm.enter(&program, &Label::function("_fob_"), vec![]),
+            Err("Entry point $$Function__fob_$$ not found in the program".to_string())
+        );
+    }
+
+    #[test]
+    fn test_push_pop_ret() {
+        let mut vm = Vm::default();
+        let program = r#"
+            lbl $$Function__double_$$
+            pop _n_
+            * _n_ 2 push
+            ret
+            lbl $$Function__main_$$
+            push 4
+            call $$Function__double_$$
+            ret
+        "#
+        .parse::<Program>()
+        .unwrap();
+
+        assert_eq!(vm.run(&program), Ok(Value::Float(8.0)));
+    }
+
+    #[test]
+    fn test_ret_closes_blocks() {
+        let mut vm = Vm::default();
+        let program = r#"
+            lbl $$Function__f_$$
+            pop _n_
+            in
+            + _n_ 1 _n_
+            in
+            mov _x_ 1
+            push _n_
+            ret
+            lbl $$Function__main_$$
+            mov _n_ 10
+            in
+            push 4
+            call $$Function__f_$$
+            pop _r_
+            + _r_ _n_ _n_
+            out
+            push _n_
+            out
+        "#
+        .parse::<Program>()
+        .unwrap();
+
+        assert_eq!(vm.run(&program), Ok(Value::Float(15.0)));
+        assert!(vm.scopes().is_empty());
+    }
+
+    #[test]
+    fn test_block_variables() {
+        let mut vm = Vm::default();
+        let program = r#"
+            lbl $$Function__main_$$
+            in
+            mov _r_ 1
+            out
+            push _r_
+            out
+        "#
+        .parse::<Program>()
+        .unwrap();
+
+        assert_eq!(
+            vm.run(&program),
+            Err("Variable _r_ not found in scope: []".to_string())
+        );
+    }
+
+    #[test]
+    fn test_halt() {
+        let mut vm = Vm::default();
+        let program = r#"
+            lbl $$Function__f_$$
+            halt pop
+            lbl $$Function__main_$$
+            mov push 3
+            call $$Function__f_$$
+            mov push 0
+            out
+        "#
+        .parse::<Program>()
+        .unwrap();
+
+        assert_eq!(vm.run(&program), Ok(Value::Float(3.0)));
+        assert_eq!(vm.depth(), 2);
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut vm = Vm::default();
+        let program = r#"
+            lbl $$Function__f_$$
+            mov _x_ pop
+            - 1 _x_ push
+            out
+            lbl $$Function__main_$$
+            mov push "a"
+            call $$Function__f_$$
+            out
+        "#
+        .parse::<Program>()
+        .unwrap();
+
+        assert!(vm.run(&program).is_err());
+        assert_eq!(
+            vm.backtrace(),
+            Backtrace {
+                frames: vec![
+                    (Label::new("$$Function__f_$$"), 2),
+                    (Label::new("$$Function__main_$$"), 6),
+                ],
+                locals: vec![("_x_".to_string(), Value::String("a".into()))],
+            }
         );
     }
 }
@@ -0,0 +1,62 @@
+use serde_json::Value as Json;
+use std::io::{BufRead, Write};
+
+/// The next JSON message framed by a `Content-Length` header, as sent by
+/// debug adapter and language server clients, or `None` once the client
+/// closed the stream.
+pub fn read(reader: &mut impl BufRead) -> Result<Option<Json>, String> {
+    let mut length = None;
+
+    loop {
+        let mut header = String::new();
+        let read = reader
+            .read_line(&mut header)
+            .map_err(|e| format!("Failed to read message: {}", e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+
+    let length = length.ok_or("Message without a Content-Length header")?;
+    let mut body = vec![0; length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|e| format!("Failed to read message: {}", e))?;
+
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| format!("Invalid message: {}", e))
+}
+
+pub fn write(writer: &mut impl Write, message: &Json) -> Result<(), String> {
+    let body = message.to_string();
+
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+        .and_then(|_| writer.flush())
+        .map_err(|e| format!("Failed to write message: {}", e))
+}
+
+/// Frames each of `messages`, e.g. to feed a server in tests.
+#[cfg(test)]
+pub fn frame(messages: &[Json]) -> Vec<u8> {
+    let mut out = vec![];
+    for message in messages {
+        write(&mut out, message).unwrap();
+    }
+    out
+}
+
+/// Reads back every message in `bytes`.
+#[cfg(test)]
+pub fn unframe(bytes: &[u8]) -> Vec<Json> {
+    let mut reader = bytes;
+    std::iter::from_fn(|| read(&mut reader).unwrap()).collect()
+}